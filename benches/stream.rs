@@ -0,0 +1,112 @@
+//! Times `json-flatten` over a large stream of small records, and over a single large record with
+//! and without `--low-memory`.  Run with `cargo bench`.
+//!
+//! For the large record, the peak memory use of `json-flatten` is printed too (on Linux).  With
+//! `--low-memory` the flat record is never built, and unless `--strict` is given the flattened keys
+//! aren't kept either, which saves about a third of the peak for this record (about 330MB buffered
+//! and 220MB with `--low-memory` when last measured).  Most of the rest is the parsed input
+//! record, which is still held.  With `--strict` every flattened key is kept to find duplicates,
+//! so the saving is smaller.
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+const RECORDS: usize = 500_000;
+const LARGE_RECORD_KEYS: usize = 200_000;
+
+/// Run `cmd` to completion, returning its peak resident set size in kB if it can be read from
+/// `/proc`.  The peak is polled while the command runs, so it may be slightly underestimated.
+fn run_with_peak_rss(cmd: &mut Command) -> Option<u64> {
+    let mut child = cmd.spawn().unwrap();
+    let status = format!("/proc/{}/status", child.id());
+    let mut peak = None;
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            assert!(status.success());
+            return peak;
+        }
+        if let Ok(s) = std::fs::read_to_string(&status) {
+            peak = s
+                .lines()
+                .find_map(|l| l.strip_prefix("VmHWM:"))
+                .and_then(|kb| kb.trim().trim_end_matches("kB").trim().parse().ok())
+                .or(peak);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+}
+
+fn main() {
+    let path = std::env::temp_dir().join("json-tools-bench-stream.json");
+    let mut file = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+    for i in 0..RECORDS {
+        writeln!(
+            file,
+            r#"{{"id":{},"name":"rec{}","tags":["a","b"],"nested":{{"x":{},"y":{{"z":{},"w":null}}}}}}"#,
+            i,
+            i,
+            i as f64 / 7.0,
+            i % 7
+        )
+        .unwrap();
+    }
+    drop(file);
+
+    for (name, stdin) in [("file", false), ("stdin", true)] {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_json-flatten"));
+        cmd.stdout(Stdio::null());
+        if stdin {
+            cmd.stdin(std::fs::File::open(&path).unwrap());
+        } else {
+            cmd.arg(&path);
+        }
+        let start = Instant::now();
+        let status = cmd.status().unwrap();
+        assert!(status.success());
+        println!(
+            "json-flatten ({}): {} records in {:.3}s",
+            name,
+            RECORDS,
+            start.elapsed().as_secs_f64()
+        );
+    }
+
+    std::fs::remove_file(&path).unwrap();
+
+    let path = std::env::temp_dir().join("json-tools-bench-large.json");
+    let mut file = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+    write!(file, "{{").unwrap();
+    for i in 0..LARGE_RECORD_KEYS {
+        if i > 0 {
+            write!(file, ",").unwrap();
+        }
+        write!(
+            file,
+            r#""k{}":{{"name":"rec{}","tags":["a","b"],"x":{}}}"#,
+            i, i, i
+        )
+        .unwrap();
+    }
+    writeln!(file, "}}").unwrap();
+    drop(file);
+
+    for (name, args) in [
+        ("buffered", &[][..]),
+        ("--low-memory", &["--low-memory"][..]),
+        ("--low-memory --strict", &["--low-memory", "--strict"][..]),
+    ] {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_json-flatten"));
+        cmd.args(args).arg(&path).stdout(Stdio::null());
+        let start = Instant::now();
+        let peak = run_with_peak_rss(&mut cmd);
+        println!(
+            "json-flatten ({}): 1 record with {} keys in {:.3}s, peak RSS {}",
+            name,
+            LARGE_RECORD_KEYS * 4,
+            start.elapsed().as_secs_f64(),
+            peak.map_or("unknown".to_string(), |kb| format!("{} kB", kb))
+        );
+    }
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -1,34 +1,155 @@
 use std::{
+    cell::{Cell, RefCell},
     fmt::{Display, Write as FmtWrite},
-    io::{self, Read, StdoutLock, Write},
+    io::{Read, Write},
     path::PathBuf,
+    str::FromStr,
 };
 
-use indexmap::IndexMap;
-use json_tools::ValueExt;
+use indexmap::{IndexMap, IndexSet};
+use json_tools::{InputOptions, Output, ValueExt};
 use posix_cli_utils::*;
-use serde_json::{de::IoRead, Value};
-
-v_escape::new!(EscapeQuotes; '"' -> r#"\""#);
+use serde_json::{de::IoRead, Map, Value};
 
 #[derive(Debug, Clone, Parser)]
 struct ClArgs {
-    /// Input JSON file (defaults to STDIN)
-    input: Option<PathBuf>,
+    #[clap(flatten)]
+    input: InputOptions,
     #[clap(flatten)]
     options: Json2Csv,
+    /// Write output to FILE instead of STDOUT
+    #[clap(short = 'o', long = "output", value_name = "FILE")]
+    output: Option<PathBuf>,
 }
 
 /// Convert a stream of JSON object records to CSV, one object per row.
 #[derive(Clone, Debug, Args)]
 struct Json2Csv {
-    /// Set the output CSV delimiter
+    /// Set the output CSV delimiter.  The escape sequences `\t`, `\n`, `\0` and `\\` can be used for
+    /// a tab, newline, NUL or backslash.
     #[clap(short = 'd', default_value = ",")]
-    delimiter: String,
+    delimiter: Delimiter,
     /// Put strings in double quotes, escaping double quotes with backslashes.
     /// For example `this, string " has, commas and quotes` becomes `"this, string \" has, commas and quotes"`
     #[clap(short = 'q')]
     quote_strings: bool,
+    /// Quote character to use with -q.  This is also the character which gets escaped inside
+    /// strings, so with `--quote-char "'"` single quotes are escaped and double quotes are not.
+    #[clap(
+        long,
+        value_name = "CHAR",
+        default_value = "\"",
+        requires = "quote-strings"
+    )]
+    quote_char: char,
+    /// Only print the header line, ie. the columns the CSV would have
+    #[clap(long)]
+    header_only: bool,
+    /// Join arrays of scalars into a single field, separated by SEP (default `|`).  Other arrays
+    /// are still dropped.
+    #[clap(
+        long,
+        value_name = "SEP",
+        min_values = 0,
+        max_values = 1,
+        require_equals = true,
+        default_missing_value = "|"
+    )]
+    join_arrays: Option<String>,
+    /// Comma-separated list of the columns to write, in order.  Other keys are dropped.  Since the
+    /// header is known up front, each row is written as soon as its record is read.
+    #[clap(
+        long,
+        value_name = "COLUMNS",
+        use_value_delimiter = true,
+        conflicts_with = "header-only"
+    )]
+    columns: Vec<String>,
+    /// Write floating point numbers with a fixed number of digits after the decimal point, instead
+    /// of the shortest form (which may use scientific notation, eg. `1e-7`).  FMT is either the
+    /// number of digits or a printf-style `%.Nf`.  Integers are written as they are.
+    #[clap(long, value_name = "FMT")]
+    float_format: Option<FloatFormat>,
+    /// Fail if a record doesn't have the same keys as the first record (in any order), instead of
+    /// leaving the missing fields empty
+    #[clap(long, alias = "strict-objects")]
+    strict: bool,
+    /// Set once a warning about the delimiter appearing in a field has been printed
+    #[clap(skip)]
+    warned: Cell<bool>,
+    /// With --strict, the keys of the first record
+    #[clap(skip)]
+    first_keys: RefCell<Option<IndexSet<String>>>,
+    /// The number of records read so far
+    #[clap(skip)]
+    records: Cell<usize>,
+}
+
+/// Parse a delimiter, replacing escape sequences, see `Json2Csv::delimiter`.
+fn parse_delimiter(s: &str) -> Result<String> {
+    let mut delimiter = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            delimiter.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => delimiter.push('\t'),
+            Some('n') => delimiter.push('\n'),
+            Some('0') => delimiter.push('\0'),
+            Some('\\') => delimiter.push('\\'),
+            Some(c) => bail!("unknown escape sequence `\\{}` in delimiter", c),
+            None => bail!("delimiter ends with a `\\`, use `\\\\` for a backslash"),
+        }
+    }
+    if delimiter.is_empty() {
+        bail!("delimiter can't be empty");
+    }
+    Ok(delimiter)
+}
+
+/// An output delimiter, see `Json2Csv::delimiter`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Delimiter {
+    text: String,
+    /// Whether the delimiter can appear in a number, ie. it only has characters which numbers are
+    /// written with.  Otherwise numbers don't need to be checked for it.
+    in_numbers: bool,
+}
+
+impl FromStr for Delimiter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let text = parse_delimiter(s)?;
+        let in_numbers = text
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | 'e' | 'E'));
+        Ok(Delimiter { text, in_numbers })
+    }
+}
+
+/// A fixed number of digits after the decimal point, see `--float-format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FloatFormat(usize);
+
+impl FromStr for FloatFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let digits = match s.strip_prefix("%.") {
+            Some(rest) => rest.strip_suffix('f'),
+            None => Some(s),
+        };
+        match digits.and_then(|d| d.parse().ok()) {
+            Some(digits) => Ok(FloatFormat(digits)),
+            None => bail!(
+                "expected a number of digits or `%.Nf`, eg. `6` or `%.6f`: {}",
+                s
+            ),
+        }
+    }
 }
 
 fn write_delimited<W, I>(mut writer: W, values: I, delim: &str) -> Result<()>
@@ -53,7 +174,25 @@ pub enum OutputField {
     Bool(bool),
     Number(serde_json::Number),
     String(String),
-    QuotedString(String),
+    QuotedString(String, char),
+}
+
+/// Displays a string with each occurrence of a quote character escaped with a backslash
+struct Escaped<'a>(&'a str, char);
+
+impl Display for Escaped<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = self.0.split(self.1);
+        if let Some(part) = parts.next() {
+            f.write_str(part)?;
+        }
+        for part in parts {
+            f.write_char('\\')?;
+            f.write_char(self.1)?;
+            f.write_str(part)?;
+        }
+        Ok(())
+    }
 }
 
 impl Display for OutputField {
@@ -65,40 +204,133 @@ impl Display for OutputField {
             Bool(true) => f.write_char('1'),
             Number(n) => Display::fmt(n, f),
             String(s) => Display::fmt(s, f),
-            QuotedString(s) => {
-                f.write_char('"')?;
-                Display::fmt(&escape(s), f)?;
-                f.write_char('"')?;
+            QuotedString(s, quote) => {
+                f.write_char(*quote)?;
+                Display::fmt(&Escaped(s, *quote), f)?;
+                f.write_char(*quote)?;
                 Ok(())
             }
         }
     }
 }
 
+impl OutputField {
+    /// Convert a scalar JSON value, leaving strings unquoted.  Arrays and objects become `Empty`.
+    fn from_scalar(value: Value) -> Self {
+        match value {
+            Value::String(s) => OutputField::String(s),
+            Value::Bool(b) => OutputField::Bool(b),
+            Value::Number(n) => OutputField::Number(n),
+            Value::Null | Value::Array(_) | Value::Object(_) => OutputField::Empty,
+        }
+    }
+}
+
+fn is_scalar(value: &Value) -> bool {
+    !matches!(value, Value::Array(_) | Value::Object(_))
+}
+
 impl Json2Csv {
-    fn run(&self, input: impl Read, mut output: StdoutLock) -> Result<()> {
-        let mut header = IndexMap::new();
-        let mut rows = Vec::new();
+    fn join(&self, items: Vec<Value>, sep: &str) -> OutputField {
+        let mut joined = String::new();
+        for (i, item) in items.into_iter().enumerate() {
+            if i > 0 {
+                joined.push_str(sep);
+            }
+            write!(joined, "{}", self.scalar(item)).unwrap();
+        }
+        if self.quote_strings {
+            OutputField::QuotedString(joined, self.quote_char)
+        } else {
+            OutputField::String(joined)
+        }
+    }
+
+    /// Convert a field of a record, returning `None` if it should be dropped.
+    fn field(&self, value: Value) -> Option<OutputField> {
+        let field = match value {
+            Value::Array(items) => match &self.join_arrays {
+                Some(sep) if items.iter().all(is_scalar) => self.join(items, sep),
+                _ => return None,
+            },
+            Value::Object(_) => return None,
+            Value::String(s) if self.quote_strings => OutputField::QuotedString(s, self.quote_char),
+            scalar => self.scalar(scalar),
+        };
+        Some(field)
+    }
+
+    /// Convert a scalar JSON value like `OutputField::from_scalar`, formatting floats as given
+    /// by `--float-format`.
+    fn scalar(&self, value: Value) -> OutputField {
+        match (value, self.float_format) {
+            (Value::Number(n), Some(FloatFormat(digits))) if n.is_f64() => {
+                OutputField::String(format!("{:.*}", digits, n.as_f64().unwrap()))
+            }
+            (value, _) => OutputField::from_scalar(value),
+        }
+    }
 
+    /// Count a record, and with --strict, fail if its keys aren't the same as the first record's.
+    fn check_keys(&self, object: &Map<String, Value>) -> Result<()> {
+        let index = self.records.get();
+        self.records.set(index + 1);
+        if !self.strict {
+            return Ok(());
+        }
+        let mut first_keys = self.first_keys.borrow_mut();
+        let first_keys = match &*first_keys {
+            Some(keys) => keys,
+            None => {
+                *first_keys = Some(object.keys().cloned().collect());
+                return Ok(());
+            }
+        };
+        let missing: Vec<_> = first_keys
+            .iter()
+            .filter(|k| !object.contains_key(*k))
+            .collect();
+        let extra: Vec<_> = object.keys().filter(|k| !first_keys.contains(*k)).collect();
+        if missing.is_empty() && extra.is_empty() {
+            return Ok(());
+        }
+        let mut problems = Vec::new();
+        if !missing.is_empty() {
+            problems.push(format!("missing {:?}", missing));
+        }
+        if !extra.is_empty() {
+            problems.push(format!("extra {:?}", extra));
+        }
+        bail!(
+            "record {} doesn't have the same keys as the first record: {}",
+            index,
+            problems.join(", ")
+        )
+    }
+
+    /// Read the records in `input`, adding their columns to `header` and their fields to `rows`.
+    fn read(
+        &self,
+        input: impl Read,
+        header: &mut IndexMap<String, usize>,
+        rows: &mut Vec<Vec<OutputField>>,
+    ) -> Result<()> {
         for value in serde_json::Deserializer::new(IoRead::new(input)).into_iter::<Value>() {
-            let object = match value? {
-                Value::Object(m) => m,
-                other => bail!("expected JSON object, not {}", other.type_name()),
-            };
+            let object = expect_object(value?)?;
+            self.check_keys(&object)?;
+            if self.header_only {
+                for (key, value) in object {
+                    if !header.contains_key(&key) && self.field(value).is_some() {
+                        header.insert(key, header.len());
+                    }
+                }
+                continue;
+            }
             let mut row = vec![OutputField::Empty; header.len()];
             for (key, value) in object {
-                let value = match value {
-                    Value::Array(_) | Value::Object(_) => continue,
-                    Value::String(s) => {
-                        if self.quote_strings {
-                            OutputField::QuotedString(s)
-                        } else {
-                            OutputField::String(s)
-                        }
-                    }
-                    Value::Bool(b) => OutputField::Bool(b),
-                    Value::Number(n) => OutputField::Number(n),
-                    Value::Null => OutputField::Empty,
+                let value = match self.field(value) {
+                    Some(value) => value,
+                    None => continue,
                 };
 
                 if let Some(idx) = header.get(&key).copied() {
@@ -111,41 +343,357 @@ impl Json2Csv {
             }
             rows.push(row);
         }
+        Ok(())
+    }
+
+    /// Write each record in `input` as a row as soon as it is read, keeping only the fields in
+    /// the fixed `header`.
+    fn stream(
+        &self,
+        input: impl Read,
+        header: &IndexMap<String, usize>,
+        mut output: impl Write,
+    ) -> Result<()> {
+        let mut row = Vec::with_capacity(header.len());
+        for value in serde_json::Deserializer::new(IoRead::new(input)).into_iter::<Value>() {
+            row.clear();
+            row.resize(header.len(), OutputField::Empty);
+            let object = expect_object(value?)?;
+            self.check_keys(&object)?;
+            for (key, value) in object {
+                if let Some(&idx) = header.get(&key) {
+                    if let Some(value) = self.field(value) {
+                        row[idx] = value;
+                    }
+                }
+            }
+            self.write_row(&row, header.len(), &mut output)?;
+        }
+        Ok(())
+    }
 
-        let ncols = header.len();
+    fn write_header(&self, header: &IndexMap<String, usize>, mut output: impl Write) -> Result<()> {
         if self.quote_strings {
             write_delimited(
                 &mut output,
-                header.into_keys().map(OutputField::QuotedString),
-                &self.delimiter,
+                header
+                    .keys()
+                    .map(|k| OutputField::QuotedString(k.clone(), self.quote_char)),
+                &self.delimiter.text,
             )?;
         } else {
-            write_delimited(&mut output, header.keys(), &self.delimiter)?;
+            write_delimited(&mut output, header.keys(), &self.delimiter.text)?;
         }
-
         writeln!(&mut output)?;
-        for row in &rows {
-            let tail = std::iter::repeat(&OutputField::Empty).take(ncols - row.len());
-            write_delimited(&mut output, row.iter().chain(tail), &self.delimiter)?;
-            writeln!(&mut output)?;
+        Ok(())
+    }
+
+    /// Print a warning (once) if an unquoted field contains the delimiter, since the row can't be
+    /// read back correctly.
+    fn check_delimiter(&self, row: &[OutputField]) {
+        if self.warned.get() {
+            return;
+        }
+        let found = row.iter().any(|field| match field {
+            OutputField::String(s) => s.contains(&*self.delimiter.text),
+            OutputField::Number(n) if self.delimiter.in_numbers => {
+                n.to_string().contains(&*self.delimiter.text)
+            }
+            _ => false,
+        });
+        if found {
+            eprintln!(
+                "warning: the delimiter {:?} appears in an unquoted field (use -q to quote strings)",
+                self.delimiter.text
+            );
+            self.warned.set(true);
         }
+    }
+
+    /// Write `row`, padded with empty fields to `ncols` columns.
+    fn write_row(&self, row: &[OutputField], ncols: usize, mut output: impl Write) -> Result<()> {
+        self.check_delimiter(row);
+        let tail = std::iter::repeat_n(&OutputField::Empty, ncols - row.len());
+        write_delimited(&mut output, row.iter().chain(tail), &self.delimiter.text)?;
+        writeln!(&mut output)?;
+        Ok(())
+    }
 
+    fn write(
+        &self,
+        header: IndexMap<String, usize>,
+        rows: &[Vec<OutputField>],
+        mut output: impl Write,
+    ) -> Result<()> {
+        self.write_header(&header, &mut output)?;
+        for row in rows {
+            self.write_row(row, header.len(), &mut output)?;
+        }
+        output.flush()?;
         Ok(())
     }
 }
 
+/// The header given by --columns, failing if a column is given more than once.
+fn column_header(columns: &[String]) -> Result<IndexMap<String, usize>> {
+    let mut header = IndexMap::new();
+    for column in columns {
+        if header.insert(column.clone(), header.len()).is_some() {
+            bail!("column {:?} is given more than once", column);
+        }
+    }
+    Ok(header)
+}
+
+fn expect_object(value: Value) -> Result<Map<String, Value>> {
+    match value {
+        Value::Object(m) => Ok(m),
+        other => bail!("expected JSON object, not {}", other.type_name()),
+    }
+}
+
 fn main() -> Result<()> {
     reset_sigpipe();
 
     let ClArgs {
         input,
         options: json2csv,
+        output,
     } = ClArgs::parse();
-    let stdout = io::stdout();
-    let output = stdout.lock();
 
-    match Input::default_stdin(input)? {
-        Input::File(f) => json2csv.run(f, output),
-        Input::Stdin(i) => json2csv.run(i.lock(), output),
+    if !json2csv.columns.is_empty() {
+        let header = column_header(&json2csv.columns)?;
+        let mut output = Output::default_stdout(output)?;
+        json2csv.write_header(&header, &mut output)?;
+        input.for_each(|input| match input {
+            Input::File(f) => json2csv.stream(f, &header, &mut output),
+            Input::Stdin(i) => json2csv.stream(i.lock(), &header, &mut output),
+        })?;
+        output.flush()?;
+        return Ok(());
+    }
+
+    let mut header = IndexMap::new();
+    let mut rows = Vec::new();
+    input.for_each(|input| match input {
+        Input::File(f) => json2csv.read(f, &mut header, &mut rows),
+        Input::Stdin(i) => json2csv.read(i.lock(), &mut header, &mut rows),
+    })?;
+
+    json2csv.write(header, &rows, Output::default_stdout(output)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn options() -> Json2Csv {
+        Json2Csv {
+            delimiter: ",".parse().unwrap(),
+            quote_strings: false,
+            quote_char: '"',
+            header_only: false,
+            join_arrays: Some("|".to_string()),
+            columns: Vec::new(),
+            float_format: None,
+            strict: false,
+            warned: Cell::new(false),
+            first_keys: RefCell::new(None),
+            records: Cell::new(0),
+        }
+    }
+
+    fn row(options: &Json2Csv, value: Value) -> String {
+        let mut header = IndexMap::new();
+        let mut rows = Vec::new();
+        options
+            .read(value.to_string().as_bytes(), &mut header, &mut rows)
+            .unwrap();
+        let mut output = Vec::new();
+        options.write(header, &rows, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn strict() {
+        let input = r#"{"a":1,"b":2} {"b":3,"a":4} {"a":5,"c":{}}"#;
+        let read = |options: &Json2Csv| {
+            options.read(input.as_bytes(), &mut IndexMap::new(), &mut Vec::new())
+        };
+        assert!(read(&options()).is_ok());
+
+        let strict = Json2Csv {
+            strict: true,
+            ..options()
+        };
+        assert_eq!(
+            read(&strict).unwrap_err().to_string(),
+            r#"record 2 doesn't have the same keys as the first record: missing ["b"], extra ["c"]"#
+        );
+
+        let strict = Json2Csv {
+            strict: true,
+            ..options()
+        };
+        let err = strict
+            .stream(r#"{"a":1} {}"#.as_bytes(), &IndexMap::new(), Vec::new())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"record 1 doesn't have the same keys as the first record: missing ["a"]"#
+        );
+    }
+
+    #[test]
+    fn join_arrays() {
+        let value = json!({ "a": [1, "x", true, null], "b": [[1]], "c": [{ "d": 1 }], "e": 2 });
+        let dropped = Json2Csv {
+            join_arrays: None,
+            ..options()
+        };
+        assert_eq!(row(&dropped, value.clone()), "e\n2\n");
+        assert_eq!(row(&options(), value.clone()), "a,e\n1|x|1|,2\n");
+
+        let custom = Json2Csv {
+            join_arrays: Some("; ".to_string()),
+            ..options()
+        };
+        assert_eq!(row(&custom, value.clone()), "a,e\n1; x; 1; ,2\n");
+
+        let quoted = Json2Csv {
+            quote_strings: true,
+            ..options()
+        };
+        assert_eq!(
+            row(&quoted, json!({ "a": ["x", "\"y\"", 1], "b": [] })),
+            "\"a\",\"b\"\n\"x|\\\"y\\\"|1\",\"\"\n"
+        );
+    }
+
+    #[test]
+    fn quote_char() {
+        let options = Json2Csv {
+            quote_strings: true,
+            quote_char: '\'',
+            ..options()
+        };
+        assert_eq!(
+            row(&options, json!({ "a": "it's \"x\"", "b": 1 })),
+            "'a','b'\n'it\\'s \"x\"',1\n"
+        );
+
+        let parse = |args: &[&str]| ClArgs::try_parse_from(["json2csv"].iter().chain(args));
+        assert!(parse(&["-q", "--quote-char", "'"]).is_ok());
+        assert!(parse(&[]).is_ok());
+        let err = parse(&["--quote-char", "'"]).unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn header_only() {
+        let input = r#"{"a": 1, "b": {"c": 2}} {"d": [1, 2], "a": 3} {"e": "x", "f": [[1]]}"#;
+        let header = |options: &Json2Csv| {
+            let mut header = IndexMap::new();
+            let mut rows = Vec::new();
+            options
+                .read(input.as_bytes(), &mut header, &mut rows)
+                .unwrap();
+            let mut output = Vec::new();
+            options.write(header, &rows, &mut output).unwrap();
+            assert!(rows.is_empty());
+            String::from_utf8(output).unwrap()
+        };
+        let options = Json2Csv {
+            header_only: true,
+            join_arrays: None,
+            ..options()
+        };
+        assert_eq!(header(&options), "a,e\n");
+        let options = Json2Csv {
+            join_arrays: Some("|".to_string()),
+            ..options
+        };
+        assert_eq!(header(&options), "a,d,e\n");
+    }
+
+    #[test]
+    fn columns() -> Result<()> {
+        let columns = ["c", "a", "b"].map(String::from);
+        let header = column_header(&columns)?;
+        let input =
+            r#"{"a": 1, "b": "x", "c": true, "d": 4} {"b": "y", "e": 5} {"c": {}, "a": [1, 2]}"#;
+        let mut output = Vec::new();
+        let options = options();
+        options.write_header(&header, &mut output)?;
+        options.stream(input.as_bytes(), &header, &mut output)?;
+        assert_eq!(String::from_utf8(output)?, "c,a,b\n1,1,x\n,,y\n,1|2,\n");
+
+        let columns = ["a", "b", "a"].map(String::from);
+        assert_eq!(
+            column_header(&columns).unwrap_err().to_string(),
+            r#"column "a" is given more than once"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn float_format() {
+        let value = json!({ "a": 0.0000001, "b": 12, "c": [1.5, 2], "d": -2.25 });
+        assert_eq!(
+            row(&options(), value.clone()),
+            "a,b,c,d\n1e-7,12,1.5|2,-2.25\n"
+        );
+
+        for fmt in ["6", "%.6f"] {
+            let options = Json2Csv {
+                float_format: Some(fmt.parse().unwrap()),
+                ..options()
+            };
+            assert_eq!(
+                row(&options, value.clone()),
+                "a,b,c,d\n0.000000,12,1.500000|2,-2.250000\n"
+            );
+        }
+        assert!("%.6".parse::<FloatFormat>().is_err());
+        assert!("-1".parse::<FloatFormat>().is_err());
+    }
+
+    #[test]
+    fn delimiter_escapes() {
+        assert_eq!(parse_delimiter(r"\t").unwrap(), "\t");
+        assert_eq!(parse_delimiter(r"\0").unwrap(), "\0");
+        assert_eq!(parse_delimiter(r";\n").unwrap(), ";\n");
+        assert_eq!(parse_delimiter(r"\\|").unwrap(), "\\|");
+        assert!(parse_delimiter("").is_err());
+        assert!(parse_delimiter(r"\x").is_err());
+        assert!(parse_delimiter(r"a\").is_err());
+
+        let options = Json2Csv {
+            delimiter: r"\t".parse().unwrap(),
+            ..options()
+        };
+        assert_eq!(
+            row(&options, json!({ "a": "x,y", "b": 1 })),
+            "a\tb\nx,y\t1\n"
+        );
+        assert!(!options.warned.get());
+        assert_eq!(row(&options, json!({ "a": "x\ty" })), "a\nx\ty\n");
+        assert!(options.warned.get());
+    }
+
+    #[test]
+    fn numbers_containing_delimiter() {
+        // Numbers are only checked when they could contain the delimiter
+        assert!(!options().delimiter.in_numbers);
+        let dot = Json2Csv {
+            delimiter: ".".parse().unwrap(),
+            ..options()
+        };
+        assert!(dot.delimiter.in_numbers);
+        row(&dot, json!({ "a": 1 }));
+        assert!(!dot.warned.get());
+        row(&dot, json!({ "a": 1.5 }));
+        assert!(dot.warned.get());
     }
 }
@@ -0,0 +1,229 @@
+use std::io::{BufReader, Read, Write};
+
+use json_tools::*;
+use posix_cli_utils::*;
+use serde::Deserialize;
+use serde_json::{Map, Number, Value};
+use serde_yaml::Value as Yaml;
+
+/// Convert a stream of JSON records to YAML documents separated by `---`, or back again.
+#[derive(Debug, Clone, Args)]
+struct Json2Yaml {
+    /// Convert YAML to JSON instead, writing each YAML document as a JSON record.  Anchors and
+    /// `<<` merge keys are resolved and tags are dropped.  Scalars JSON has no equivalent for
+    /// (`.nan`, `.inf`) become strings, as do scalar keys which aren't strings.
+    #[clap(short = 'r', long)]
+    reverse: bool,
+}
+
+#[derive(Debug, Clone, Parser)]
+struct ClArgs {
+    #[clap(flatten)]
+    input: InputOptions,
+    #[clap(flatten)]
+    options: Json2Yaml,
+    #[clap(flatten)]
+    output: OutputOptions,
+}
+
+fn reader(input: Input<Box<dyn Read>>) -> Box<dyn Read> {
+    match input {
+        Input::File(f) => Box::new(BufReader::new(f)),
+        Input::Stdin(i) => Box::new(i.lock()),
+    }
+}
+
+/// Call `f` with each JSON record in `input`.
+fn read_json(input: impl Read, mut f: impl FnMut(Value) -> Result<()>) -> Result<()> {
+    for value in serde_json::Deserializer::from_reader(input).into_iter::<Value>() {
+        f(value?)?;
+    }
+    Ok(())
+}
+
+/// Call `f` with each YAML document in `input`, converted to JSON.
+fn read_yaml(input: impl Read, mut f: impl FnMut(Value) -> Result<()>) -> Result<()> {
+    for document in serde_yaml::Deserializer::from_reader(input) {
+        let mut value = Yaml::deserialize(document)?;
+        value.apply_merge()?;
+        f(yaml_to_json(value)?)?;
+    }
+    Ok(())
+}
+
+fn yaml_to_json(value: Yaml) -> Result<Value> {
+    let value = match value {
+        Yaml::Null => Value::Null,
+        Yaml::Bool(b) => Value::Bool(b),
+        Yaml::Number(n) => yaml_number(&n),
+        Yaml::String(s) => Value::String(s),
+        Yaml::Sequence(items) => {
+            Value::Array(items.into_iter().map(yaml_to_json).collect::<Result<_>>()?)
+        }
+        Yaml::Mapping(mapping) => {
+            let mut map = Map::new();
+            for (key, value) in mapping {
+                map.insert(yaml_key(key)?, yaml_to_json(value)?);
+            }
+            Value::Object(map)
+        }
+        Yaml::Tagged(tagged) => yaml_to_json(tagged.value)?,
+    };
+    Ok(value)
+}
+
+fn yaml_number(n: &serde_yaml::Number) -> Value {
+    let number = if let Some(n) = n.as_u64() {
+        Some(Number::from(n))
+    } else if let Some(n) = n.as_i64() {
+        Some(Number::from(n))
+    } else {
+        n.as_f64().and_then(Number::from_f64)
+    };
+    match number {
+        Some(number) => Value::Number(number),
+        None => Value::String(n.to_string()),
+    }
+}
+
+/// The JSON object key for a YAML mapping key.
+fn yaml_key(key: Yaml) -> Result<String> {
+    let key = match key {
+        Yaml::Null => "null".to_string(),
+        Yaml::Bool(b) => b.to_string(),
+        Yaml::Number(n) => n.to_string(),
+        Yaml::String(s) => s,
+        Yaml::Tagged(tagged) => yaml_key(tagged.value)?,
+        Yaml::Sequence(_) | Yaml::Mapping(_) => {
+            bail!("cannot convert a YAML mapping key which is a sequence or mapping to JSON")
+        }
+    };
+    Ok(key)
+}
+
+fn json_to_yaml(value: Value) -> Yaml {
+    match value {
+        Value::Null => Yaml::Null,
+        Value::Bool(b) => Yaml::Bool(b),
+        Value::Number(n) => Yaml::Number(if let Some(n) = n.as_u64() {
+            n.into()
+        } else if let Some(n) = n.as_i64() {
+            n.into()
+        } else {
+            n.as_f64().unwrap_or(f64::NAN).into()
+        }),
+        Value::String(s) => Yaml::String(s),
+        Value::Array(items) => Yaml::Sequence(items.into_iter().map(json_to_yaml).collect()),
+        Value::Object(map) => Yaml::Mapping(
+            map.into_iter()
+                .map(|(k, v)| (Yaml::String(k), json_to_yaml(v)))
+                .collect(),
+        ),
+    }
+}
+
+/// Write `value` as a YAML document, preceded by `---` unless it is the first.
+fn write_yaml(w: &mut dyn Write, value: Value, first: bool) -> Result<()> {
+    if !first {
+        w.write_all(b"---\n")?;
+    }
+    serde_yaml::to_writer(w, &json_to_yaml(value))?;
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    reset_sigpipe();
+    let args = ClArgs::parse();
+    let mut count = 0;
+    args.output.with_output(|w| {
+        args.input.for_each(|input| {
+            let mut write = |value: Value| -> Result<()> {
+                if args.options.reverse {
+                    args.output.serialize_to(&mut *w, &value)?;
+                    writeln!(w)?;
+                } else {
+                    write_yaml(w, value, count == 0)?;
+                }
+                count += 1;
+                Ok(())
+            };
+            if args.options.reverse {
+                read_yaml(reader(input), &mut write)
+            } else {
+                read_json(reader(input), &mut write)
+            }
+        })
+    })?;
+    if args.output.count {
+        eprintln!("{}", count);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn from_yaml(s: &str) -> Result<Vec<Value>> {
+        let mut values = Vec::new();
+        read_yaml(s.as_bytes(), |v| {
+            values.push(v);
+            Ok(())
+        })?;
+        Ok(values)
+    }
+
+    fn to_yaml(values: &[Value]) -> Result<String> {
+        let mut output = Vec::new();
+        for (i, v) in values.iter().enumerate() {
+            write_yaml(&mut output, v.clone(), i == 0)?;
+        }
+        Ok(String::from_utf8(output)?)
+    }
+
+    #[test]
+    fn documents() -> Result<()> {
+        let values = from_yaml("a: 1\nb: [x, y]\n---\n- 1.5\n- null\n---\nplain\n")?;
+        assert_eq!(
+            values,
+            [
+                json!({ "a": 1, "b": ["x", "y"] }),
+                json!([1.5, null]),
+                json!("plain")
+            ]
+        );
+        assert_eq!(
+            to_yaml(&values)?,
+            "a: 1\nb:\n- x\n- y\n---\n- 1.5\n- null\n---\nplain\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn yaml_scalars() -> Result<()> {
+        let values = from_yaml(
+            "date: 2024-01-31\nnan: .nan\ninf: -.inf\ntagged: !thing 5\n1: one\ntrue: yes\n~: null\n",
+        )?;
+        assert_eq!(
+            serde_json::to_string(&values[0])?,
+            r#"{"date":"2024-01-31","nan":".nan","inf":"-.inf","tagged":5,"1":"one","true":"yes","null":null}"#
+        );
+
+        let values = from_yaml("base: &base { a: 1, b: 2 }\nderived: { <<: *base, b: 3 }\n")?;
+        assert_eq!(values[0]["derived"], json!({ "b": 3, "a": 1 }));
+
+        assert!(from_yaml("? [a, b]\n: c\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let values = [
+            json!({ "s": "123", "t": "true", "d": "2024-01-31", "e": "", "n": -7, "f": 0.25 }),
+            json!({ "nested": { "list": [[], {}, "a: b", "- x"] } }),
+        ];
+        assert_eq!(from_yaml(&to_yaml(&values)?)?, values);
+        Ok(())
+    }
+}
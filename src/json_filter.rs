@@ -0,0 +1,270 @@
+use std::{io::Write, path::PathBuf, str::FromStr};
+
+use json_tools::*;
+use posix_cli_utils::*;
+use regex::Regex;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+}
+
+/// A comparison of the value at a (flattened) key against a constant, eg `a.b == 5`
+#[derive(Debug, Clone, PartialEq)]
+struct Condition {
+    key: String,
+    op: Op,
+    value: Value,
+}
+
+impl FromStr for Condition {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let re = Regex::new(r"^\s*(.+?)\s*(==|!=|<|>)\s*(.+?)\s*$").unwrap();
+        let captures = re
+            .captures(s)
+            .ok_or_else(|| anyhow!("expected a condition of the form `KEY OP VALUE`: {}", s))?;
+        let op = match &captures[2] {
+            "==" => Op::Eq,
+            "!=" => Op::Ne,
+            "<" => Op::Lt,
+            ">" => Op::Gt,
+            _ => unreachable!(),
+        };
+        let value = &captures[3];
+        let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.into()));
+        Ok(Condition {
+            key: captures[1].to_string(),
+            op,
+            value,
+        })
+    }
+}
+
+impl Condition {
+    fn matches(&self, value: &Value) -> bool {
+        use std::cmp::Ordering;
+
+        let ordering = match (value, &self.value) {
+            (Value::Number(a), Value::Number(b)) => match (a.as_f64(), b.as_f64()) {
+                (Some(a), Some(b)) => a.partial_cmp(&b),
+                _ => None,
+            },
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            (a, b) if a == b => Some(Ordering::Equal),
+            _ => None,
+        };
+
+        match self.op {
+            Op::Eq => ordering == Some(Ordering::Equal),
+            Op::Ne => ordering != Some(Ordering::Equal),
+            Op::Lt => ordering == Some(Ordering::Less),
+            Op::Gt => ordering == Some(Ordering::Greater),
+        }
+    }
+}
+
+/// Only output records which match every condition.
+#[derive(Debug, Clone, Args)]
+struct Filter {
+    /// Keep records where the comparison `KEY OP VALUE` holds, where OP is one of `==`, `!=`, `<` or `>`.
+    /// VALUE is parsed as JSON if possible, otherwise it is treated as a string.  Comparisons
+    /// against a key which doesn't exist never hold.
+    #[clap(short = 'w', long = "where", value_name = "CONDITION")]
+    conditions: Vec<Condition>,
+    /// Keep records where the key exists
+    #[clap(long, value_name = "KEY")]
+    has: Vec<String>,
+    /// Separator used to split keys into nested keys and array indices
+    #[clap(short = 'd', default_value = ".")]
+    sep: String,
+    /// Keep records which don't match instead
+    #[clap(short = 'v', long)]
+    invert: bool,
+    /// Write the records which aren't kept to FILE, so that the input is split between the output
+    /// and FILE.  FILE is compressed like the output, with --gzip-out or --zstd-out.
+    #[clap(long, value_name = "FILE")]
+    rejects: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Parser)]
+struct ClArgs {
+    #[clap(flatten)]
+    input: InputOptions,
+    #[clap(flatten)]
+    options: Filter,
+    #[clap(flatten)]
+    output: OutputOptions,
+}
+
+impl Filter {
+    fn lookup<'a>(&self, value: &'a Value, key: &str) -> Option<&'a Value> {
+        value.get_path(key.split(&*self.sep))
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        self.has.iter().all(|k| self.lookup(value, k).is_some())
+            && self
+                .conditions
+                .iter()
+                .all(|c| matches!(self.lookup(value, &c.key), Some(v) if c.matches(v)))
+    }
+}
+
+/// A filter, with the file rejected records are written to (formatted and compressed like the
+/// output)
+struct Partition {
+    filter: Filter,
+    rejects: Option<(OutputFile, OutputOptions)>,
+}
+
+impl Partition {
+    fn new(filter: Filter, output: &OutputOptions) -> Result<Self> {
+        let rejects = match &filter.rejects {
+            Some(path) => Some((output.create_file(path)?, output.clone())),
+            None => None,
+        };
+        Ok(Partition { filter, rejects })
+    }
+
+    fn finish(self) -> Result<()> {
+        if let Some((rejects, _)) = self.rejects {
+            rejects.finish()?;
+        }
+        Ok(())
+    }
+}
+
+impl RunStreamJson for Partition {
+    fn process_one<S>(&mut self, value: Value, output: S) -> Result<Option<S::Ok>>
+    where
+        S: Serializer,
+        S::Error: Send + Sync + 'static,
+    {
+        if self.filter.matches(&value) != self.filter.invert {
+            return Ok(Some(value.serialize(output)?));
+        }
+        if let Some((rejects, format)) = &mut self.rejects {
+            format.serialize_to(&mut *rejects, &value)?;
+            rejects.write_all(b"\n")?;
+        }
+        Ok(None)
+    }
+}
+
+fn main() -> Result<()> {
+    reset_sigpipe();
+    let args = ClArgs::parse();
+    let mut partition = Partition::new(args.options, &args.output)?;
+    partition.main(&args.input, &args.output)?;
+    partition.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Read;
+
+    fn filter(conditions: &[&str], has: &[&str]) -> Filter {
+        Filter {
+            conditions: conditions.iter().map(|c| c.parse().unwrap()).collect(),
+            has: has.iter().map(|k| k.to_string()).collect(),
+            sep: ".".to_string(),
+            invert: false,
+            rejects: None,
+        }
+    }
+
+    #[test]
+    fn parse_condition() {
+        let c: Condition = r#" status != "ok" "#.parse().unwrap();
+        assert_eq!(c.key, "status");
+        assert_eq!(c.op, Op::Ne);
+        assert_eq!(c.value, json!("ok"));
+
+        let c: Condition = "a.b==5".parse().unwrap();
+        assert_eq!((c.key.as_str(), c.op, c.value), ("a.b", Op::Eq, json!(5)));
+
+        let c: Condition = "name > bob".parse().unwrap();
+        assert_eq!(
+            (c.key.as_str(), c.op, c.value),
+            ("name", Op::Gt, json!("bob"))
+        );
+
+        assert!("a.b".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn comparisons() {
+        let record = json!({ "a": { "b": 5 }, "c": [1.5, "x"], "status": "ok" });
+        assert!(filter(&["a.b == 5"], &[]).matches(&record));
+        assert!(filter(&["a.b == 5.0"], &[]).matches(&record));
+        assert!(!filter(&["a.b != 5"], &[]).matches(&record));
+        assert!(filter(&["a.b < 6", "a.b > 4"], &[]).matches(&record));
+        assert!(!filter(&["a.b < 5"], &[]).matches(&record));
+        assert!(filter(&["c.0 > 1"], &[]).matches(&record));
+        assert!(filter(&[r#"c.1 == "x""#], &[]).matches(&record));
+        assert!(!filter(&[r#"status != "ok""#], &[]).matches(&record));
+        assert!(!filter(&["a.b > x"], &[]).matches(&record));
+        assert!(!filter(&["missing != 1"], &[]).matches(&record));
+    }
+
+    #[test]
+    fn has() {
+        let record = json!({ "a": { "b": null }, "c": [1] });
+        assert!(filter(&[], &["a.b", "c.0"]).matches(&record));
+        assert!(!filter(&[], &["a.c"]).matches(&record));
+        assert!(!filter(&[], &["c.1"]).matches(&record));
+        assert!(!filter(&["a.b == null"], &["x"]).matches(&record));
+    }
+
+    #[test]
+    fn rejects() -> Result<()> {
+        let path = std::env::temp_dir().join("json-tools-test-rejects.json");
+        let records = [json!({ "a": 1 }), json!({ "a": 2 }), json!({ "b": 1 })];
+        for (invert, kept, rejected) in [
+            (false, r#"[{"a":1}]"#, "{\"a\":2}\n{\"b\":1}\n"),
+            (true, r#"[{"a":2},{"b":1}]"#, "{\"a\":1}\n"),
+        ] {
+            let filter = Filter {
+                invert,
+                rejects: Some(path.clone()),
+                ..filter(&["a == 1"], &[])
+            };
+            let mut partition = Partition::new(filter, &OutputOptions::default())?;
+            let mut output = Vec::new();
+            for r in records.clone() {
+                output.extend(partition.process_one(r, serde_json::value::Serializer)?);
+            }
+            partition.finish()?;
+            assert_eq!(serde_json::to_string(&output)?, kept);
+            assert_eq!(std::fs::read_to_string(&path)?, rejected);
+        }
+
+        let filter = Filter {
+            rejects: Some(path.clone()),
+            ..filter(&["a == 1"], &[])
+        };
+        let output = OutputOptions {
+            gzip_out: true,
+            ..Default::default()
+        };
+        let mut partition = Partition::new(filter, &output)?;
+        for r in records {
+            partition.process_one(r, serde_json::value::Serializer)?;
+        }
+        partition.finish()?;
+        let mut rejected = String::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(&path)?).read_to_string(&mut rejected)?;
+        assert_eq!(rejected, "{\"a\":2}\n{\"b\":1}\n");
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -1,184 +1,1803 @@
-use std::{collections::HashMap, fmt::Display, path::PathBuf};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fmt::Display,
+    io::{self, BufRead, BufReader},
+    str::FromStr,
+};
 
-use indexmap::IndexMap;
+use indexmap::{IndexMap, IndexSet};
 use json_tools::*;
 use posix_cli_utils::*;
-use serde::Serialize;
-use serde_json::Value;
+use regex::Regex;
+use serde::{ser::SerializeMap, Serialize};
+use serde_json::{Map, Value};
 use std::fmt::Write;
 
+/// How flattened records are written (or read, when unflattening)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// One JSON object per record
+    Json,
+    /// One `key=value` line per leaf, with records separated by blank lines
+    Kv,
+    /// One `key,value` CSV row per leaf (write only)
+    Csv,
+}
+
+/// What to do when array indices have gaps, see `--sparse`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sparse {
+    Null,
+    Error,
+    Object,
+}
+
+impl FromStr for Sparse {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "null" => Ok(Sparse::Null),
+            "error" => Ok(Sparse::Error),
+            "object" => Ok(Sparse::Object),
+            _ => bail!(
+                "unknown policy {:?} (expected `null`, `error` or `object`)",
+                s
+            ),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Format::Json),
+            "kv" => Ok(Format::Kv),
+            "csv" => Ok(Format::Csv),
+            _ => bail!("unknown format {:?} (expected `json`, `kv` or `csv`)", s),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Args)]
 struct Flatten {
     /// Separater to use when concatenating keys
     #[clap(short = 'd', default_value = ".")]
     sep: String,
+    /// Use bracket notation for array indices, eg `a[0].b` instead of `a.0.b`.  When unflattening,
+    /// keys in bracket notation are turned back into arrays.
+    #[clap(short = 'b', long, alias = "bracket-arrays")]
+    brackets: bool,
+    /// Don't flatten arrays, keep them (and everything inside them) as values
+    #[clap(short = 'a', long, conflicts_with = "brackets")]
+    keep_arrays: bool,
+    /// Drop empty objects and arrays instead of keeping them as values
+    #[clap(long)]
+    drop_empty: bool,
+    /// Drop keys whose value is `null`.  Nulls inside arrays kept with -a are left alone.
+    #[clap(long)]
+    drop_nulls: bool,
+    /// Escape separators inside keys with CHAR (default `\`) when flattening, so that eg. the key
+    /// `a.b` becomes `a\.b` rather than being mistaken for a nested key.  The escape character
+    /// itself (and `[` with --brackets) is escaped too.  When unflattening, keys are only split on
+    /// unescaped separators.
+    #[clap(
+        long,
+        value_name = "CHAR",
+        min_values = 0,
+        max_values = 1,
+        require_equals = true,
+        default_missing_value = "\\"
+    )]
+    escape: Option<char>,
+    /// Write flattened keys as JSON Pointers (RFC 6901), eg. `/a/b/0`, escaping `~` and `/` in keys
+    /// as `~0` and `~1`.  When unflattening, keys must be JSON Pointers.  `-d` is ignored.
+    #[clap(long, conflicts_with_all = &["brackets", "escape"])]
+    pointer: bool,
+    /// When unflattening, treat the separator given with -d as a regex, eg. `-d '\.|__'` to split
+    /// keys on either `.` or `__`.  Can't be used when flattening.
+    #[clap(long, conflicts_with_all = &["escape", "pointer", "fidelity"])]
+    regex_sep: bool,
+    /// When unflattening, turn objects whose keys are all array indices (`0`, `1`, ...) back into
+    /// arrays
+    #[clap(long)]
+    arrays: bool,
+    /// With --arrays, fill missing array indices with `null` instead of failing.  The same as
+    /// `--sparse null`.
+    #[clap(long, requires = "arrays")]
+    fill_gaps: bool,
+    /// With --arrays, what to do when array indices have gaps, eg. `a.0` and `a.2` without `a.1`:
+    /// `null` fills them with null, `error` fails (the default), and `object` keeps an object
+    /// with the indices as keys instead
+    #[clap(
+        long,
+        value_name = "POLICY",
+        requires = "arrays",
+        conflicts_with = "fill-gaps"
+    )]
+    sparse: Option<Sparse>,
+    /// Before flattening, treat keys containing the separator as nested keys, like TOML dotted
+    /// keys.  For example `{"a.b": 1, "a": {"c": 2}}` is read as `{"a": {"b": 1, "c": 2}}`.
+    #[clap(long)]
+    toml_dotted_keys: bool,
+    /// Only flatten the first N levels of nesting, keeping anything deeper as nested values.  With
+    /// N = 0, records are left as they are.
+    #[clap(long, value_name = "N")]
+    max_depth: Option<usize>,
+    /// Only flatten the first N elements of longer arrays, adding a `__truncated` key after them
+    /// with the length of the array, eg. `a.__truncated`.  Applies to arrays at any depth.
+    #[clap(long, value_name = "N", conflicts_with_all = &["keep-arrays", "fidelity"])]
+    max_array_len: Option<usize>,
+    /// With --max-array-len, leave out arrays longer than N altogether instead
+    #[clap(long, requires = "max-array-len")]
+    max_array_len_drop: bool,
+    /// Write objects and arrays which are kept as values (below --max-depth, empty ones, and
+    /// arrays with -a) as compact JSON strings, so that every flattened value is a scalar
+    #[clap(long, conflicts_with = "fidelity")]
+    stringify_deep: bool,
+    /// When unflattening, parse string values which are JSON objects or arrays, reversing
+    /// --stringify-deep
+    #[clap(long)]
+    parse_leaves: bool,
+    /// When unflattening, fail if two keys conflict, eg. `a` and `a.b`, instead of silently
+    /// dropping one of the values.  When flattening, fail if a key contains the separator, since
+    /// it couldn't be unflattened again (unless the separator is escaped with --escape), or if
+    /// two paths flatten to the same key.
+    #[clap(long)]
+    strict: bool,
+    /// Only flatten the value at the JSON Pointer POINTER in each record, eg. `/payload/metrics`.
+    /// Records without it are skipped with a warning, or fail with --strict.  When unflattening,
+    /// each record is nested under POINTER in an otherwise empty document instead.
+    #[clap(long, value_name = "POINTER")]
+    root: Option<String>,
+    /// With --root, start flattened keys with the path to the root, eg. `payload.metrics.a`
+    /// rather than `a`.  When unflattening, the keys are expected to include the path already.
+    #[clap(long, requires = "root")]
+    absolute_keys: bool,
+    /// Start every flattened key with PREFIX, eg. `doc.a.b` instead of `a.b` with `--prefix doc`
+    #[clap(long, value_name = "PREFIX")]
+    prefix: Option<String>,
+    /// When unflattening, remove PREFIX (and the separator after it) from the start of every key.
+    /// It's an error for a key not to start with PREFIX.
+    #[clap(long, value_name = "PREFIX")]
+    strip_prefix: Option<String>,
+    /// When unflattening, write records which aren't objects (eg. heartbeat strings or arrays) as
+    /// they are instead of failing.  With --strip-prefix, also leave keys which don't start with
+    /// the prefix as they are.
+    #[clap(long)]
+    lenient: bool,
+    /// Only keep flattened keys matching REGEX.  May be given more than once to keep keys matching
+    /// any of the patterns.
+    #[clap(long, value_name = "REGEX")]
+    include: Vec<Regex>,
+    /// Drop flattened keys matching REGEX, even if they match --include.  May be given more than
+    /// once.
+    #[clap(long, value_name = "REGEX")]
+    exclude: Vec<Regex>,
+    /// Write (or with -u, read) FORMAT, either `json`, `kv` or `csv`.  The `kv` format has one
+    /// `key=value` line per leaf, with records separated by blank lines.  Strings are written as
+    /// they are, with `\`, `=` and newlines escaped by a `\`, null is written as nothing and
+    /// anything else as JSON.  The `csv` format (which can't be read) has a `key,value` header
+    /// and one row per leaf, with fields quoted as in RFC 4180, booleans written as 1 or 0 and
+    /// null as nothing.
+    #[clap(long, value_name = "FORMAT", default_value = "json")]
+    format: Format,
+    /// With `--format csv`, add a third column with the index of the record each row came from.
+    /// Records produced by --explode are counted separately.
+    #[clap(long)]
+    record_index: bool,
+    /// When unflattening `kv` input, read every value as a string.  Otherwise empty values are
+    /// read as null, and values which are valid JSON but not a JSON string (numbers, booleans,
+    /// null, ...) are read as JSON.
+    #[clap(long)]
+    string_values: bool,
+    /// Write one record per element of the array at PATH (eg. `a.events`), in place of the array.
+    /// If given more than once, every combination of elements is written.  Records where the
+    /// array is missing or empty are written once, without it.
+    #[clap(long, value_name = "PATH")]
+    explode: Vec<String>,
+    /// With --explode, drop records where an exploded array is missing or empty
+    #[clap(long, requires = "explode")]
+    explode_drop_missing: bool,
+    /// When unflattening, merge every record into a single document, written at the end of the
+    /// input.  Conflicting keys in different records are handled like conflicts within a record.
+    #[clap(long, conflicts_with = "fidelity")]
+    merge: bool,
+    /// Write only the flattened keys of each record, one per line, instead of the record
+    #[clap(long, conflicts_with = "unflatten")]
+    keys_only: bool,
+    /// With --keys-only, write the type of each value after its key, separated by a tab
+    #[clap(long, requires = "keys-only")]
+    with_types: bool,
+    /// With --keys-only, write each key once, after all records have been read
+    #[clap(long, requires = "keys-only")]
+    unique: bool,
+    /// Write the flattened records as a CSV table, with a row for each record and a column for
+    /// each key found in any record, in the order the keys are first seen (or sorted with
+    /// --sort-keys).  Missing fields are left empty, and fields are written as with `--format
+    /// csv`.  Every record is read before anything is written.
+    #[clap(long, conflicts_with_all = &["unflatten", "keys-only", "record-index"])]
+    table: bool,
+    /// Convert each object key to CASE before joining, one of `snake` (`user_id`), `camel`
+    /// (`userId`), `kebab` (`user-id`), `lower` (`userid`) or `upper` (`USERID`).  Array indices
+    /// are left alone.  Keys which become the same are reported like any other keys which occur
+    /// more than once after flattening, even with --escape or --pointer.
+    #[clap(long, value_name = "CASE", conflicts_with_all = &["unflatten", "fidelity"])]
+    key_case: Option<KeyCase>,
+    /// Make unflattening the exact inverse of flattening, keeping empty objects and arrays, array
+    /// indices, key order, and keys containing special characters.  Implies --brackets and
+    /// --escape, writes empty keys as `""` (escaping `"` in other keys), and passes records which
+    /// can't be flattened through unchanged in both directions.
+    #[clap(long, conflicts_with_all = &["drop-empty", "drop-nulls", "keep-arrays", "pointer"])]
+    fidelity: bool,
+    /// The separator compiled with --regex-sep, see `compile_regex_sep`
+    #[clap(skip)]
+    sep_regex: Option<Regex>,
+    /// Write each flattened key as soon as it's found instead of collecting the flat record
+    /// first, which uses much less memory for very large records.  Keys which occur more than once
+    /// after flattening aren't looked for, so they are written more than once.  With --strict
+    /// they are an error as usual, but every key of the record is then held in memory to find
+    /// them.
+    #[clap(long)]
+    low_memory: bool,
+    /// Scratch space, reused between records
+    #[clap(skip)]
+    flat: IndexMap<String, Value>,
 }
 
 /// Recursively flatten a JSON object.
 #[derive(Debug, Clone, Parser)]
 struct Args {
-    /// Input JSON file (defaults to STDIN)
-    input: Option<PathBuf>,
+    #[clap(flatten)]
+    input: InputOptions,
     /// Unflatten instead
     #[clap(short = 'u')]
     unflatten: bool,
     #[clap(flatten)]
     options: Flatten,
+    #[clap(flatten)]
+    output: OutputOptions,
 }
 
 #[derive(Serialize, Clone, Debug)]
 #[serde(untagged)]
 enum UnflattenTree {
-    Branch(HashMap<String, UnflattenTree>),
+    Branch(IndexMap<String, UnflattenTree>),
+    Array(Vec<UnflattenTree>),
     Empty,
     Leaf(Value),
 }
 
+/// One component of a flattened key
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment<'a> {
+    Key(Cow<'a, str>),
+    Index(usize),
+}
+
+/// Split `key` on each `sep` which isn't escaped.  The parts are left escaped.
+fn split_unescaped<'a>(key: &'a str, sep: &str, escape: char) -> Vec<&'a str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while let Some(c) = key[i..].chars().next() {
+        if c == escape {
+            i += c.len_utf8();
+            i += key[i..].chars().next().map_or(0, char::len_utf8);
+        } else if !sep.is_empty() && key[i..].starts_with(sep) {
+            parts.push(&key[start..i]);
+            i += sep.len();
+            start = i;
+        } else {
+            i += c.len_utf8();
+        }
+    }
+    parts.push(&key[start..]);
+    parts
+}
+
+/// Returns `true` if the character following `prefix` is escaped.
+fn is_escaped(prefix: &str, escape: Option<char>) -> bool {
+    match escape {
+        Some(e) => prefix.chars().rev().take_while(|&c| c == e).count() % 2 == 1,
+        None => false,
+    }
+}
+
+fn unescape(part: &str, escape: Option<char>) -> Cow<'_, str> {
+    match escape {
+        Some(e) if part.contains(e) => {
+            let mut unescaped = String::with_capacity(part.len());
+            let mut chars = part.chars();
+            while let Some(c) = chars.next() {
+                if c == e {
+                    unescaped.extend(chars.next());
+                } else {
+                    unescaped.push(c);
+                }
+            }
+            Cow::Owned(unescaped)
+        }
+        _ => Cow::Borrowed(part),
+    }
+}
+
+/// Returns `true` if `key` is a valid JSON Pointer: empty, or starting with `/` and with every `~`
+/// followed by `0` or `1`.
+fn is_pointer(key: &str) -> bool {
+    let escapes_ok = key
+        .match_indices('~')
+        .all(|(i, _)| matches!(key.as_bytes().get(i + 1), Some(b'0' | b'1')));
+    escapes_ok && (key.is_empty() || key.starts_with('/'))
+}
+
+/// Unescape a JSON Pointer reference token.
+fn unescape_pointer(token: &str) -> Cow<'_, str> {
+    if token.contains('~') {
+        Cow::Owned(token.replace("~1", "/").replace("~0", "~"))
+    } else {
+        Cow::Borrowed(token)
+    }
+}
+
+/// Parse an array index, which must be a canonical base-10 integer (no sign or leading zeros).
+fn parse_index(s: &str) -> Option<usize> {
+    let canonical = s == "0" || !(s.is_empty() || s.starts_with('0'));
+    if canonical && s.bytes().all(|b| b.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Parse a separator-delimited part of a flattened key in bracket notation, eg `a[0][2]`.
+/// Only trailing `[N]` groups (where `N` is a canonical integer) are treated as array indices,
+/// and only if the `[` isn't escaped.  With `fidelity`, `""` is the empty key.
+fn parse_brackets<'a>(
+    part: &'a str,
+    escape: Option<char>,
+    fidelity: bool,
+    segments: &mut Vec<Segment<'a>>,
+) {
+    let start = segments.len();
+    let mut rest = part;
+    while let Some(inner) = rest.strip_suffix(']') {
+        let index = match inner.rfind('[') {
+            Some(open) if !is_escaped(&inner[..open], escape) => &inner[open + 1..],
+            _ => break,
+        };
+        match parse_index(index) {
+            Some(i) => {
+                segments.push(Segment::Index(i));
+                rest = &inner[..inner.len() - index.len() - 1];
+            }
+            None => break,
+        }
+    }
+    if fidelity && rest == "\"\"" {
+        segments.push(Segment::Key(Cow::Borrowed("")));
+    } else if !rest.is_empty() || segments.len() == start {
+        segments.push(Segment::Key(unescape(rest, escape)));
+    }
+    segments[start..].reverse();
+}
+
+/// Why a value couldn't be inserted into an `UnflattenTree` without losing another value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Conflict {
+    /// A value and a branch at the same path, eg. from the keys `a` and `a.b`
+    Nested,
+    /// Two different values at the same path, eg. from the keys `a.0` and `a[0]`
+    Duplicate,
+}
+
 impl UnflattenTree {
     fn has_children(&self) -> bool {
-        matches!(self, UnflattenTree::Branch(_))
+        matches!(self, UnflattenTree::Branch(_) | UnflattenTree::Array(_))
     }
 
-    fn insert<'a>(&mut self, mut keys: impl Iterator<Item = &'a str>, value: Value) {
-        if let Some(key) = keys.next() {
-            match self {
-                UnflattenTree::Empty | UnflattenTree::Leaf(_) => {
-                    *self = UnflattenTree::Branch({
-                        let mut m = HashMap::new();
-                        m.entry(key.to_string())
+    /// Recursively turn branches whose keys are all array indices into arrays.  If the indices
+    /// have gaps, `sparse` decides what happens.
+    fn rebuild_arrays(&mut self, sparse: Sparse) -> Result<()> {
+        match self {
+            UnflattenTree::Branch(map) => {
+                for child in map.values_mut() {
+                    child.rebuild_arrays(sparse)?;
+                }
+                let indices: Option<Vec<usize>> = map.keys().map(|k| parse_index(k)).collect();
+                let len = match indices.and_then(|i| i.into_iter().max()) {
+                    Some(max) => max + 1,
+                    None => return Ok(()),
+                };
+                if len != map.len() {
+                    match sparse {
+                        Sparse::Null => {}
+                        Sparse::Object => return Ok(()),
+                        Sparse::Error => {
+                            let missing = (0..len)
+                                .find(|i| !map.contains_key(&i.to_string()))
+                                .unwrap();
+                            bail!(
+                                "array is missing index {} ({} of {} elements present)",
+                                missing,
+                                map.len(),
+                                len
+                            );
+                        }
+                    }
+                }
+                let mut items = vec![UnflattenTree::Empty; len];
+                for (k, v) in map.drain(..) {
+                    items[parse_index(&k).unwrap()] = v;
+                }
+                *self = UnflattenTree::Array(items);
+            }
+            UnflattenTree::Array(items) => {
+                for child in items {
+                    child.rebuild_arrays(sparse)?;
+                }
+            }
+            UnflattenTree::Empty | UnflattenTree::Leaf(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Insert `value` at the path given by `keys`.  Returns the conflict if the value conflicts
+    /// with one already in the tree: a leaf is replaced by a branch or vice versa, or a leaf is
+    /// replaced by a different value.  Conflicts are resolved in favour of branches, then the
+    /// latest leaf.
+    fn insert<'a>(
+        &mut self,
+        mut keys: impl Iterator<Item = Segment<'a>>,
+        value: Value,
+    ) -> Option<Conflict> {
+        match keys.next() {
+            Some(Segment::Key(key)) => {
+                if let UnflattenTree::Array(items) = self {
+                    let map = std::mem::take(items)
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, v)| (i.to_string(), v))
+                        .collect();
+                    *self = UnflattenTree::Branch(map);
+                }
+                match self {
+                    UnflattenTree::Empty | UnflattenTree::Leaf(_) => {
+                        let replaced = self.replaced_leaf();
+                        let mut m = IndexMap::new();
+                        let conflict = m
+                            .entry(key.into_owned())
                             .or_insert(UnflattenTree::Empty)
                             .insert(keys, value);
-                        m
-                    });
+                        *self = UnflattenTree::Branch(m);
+                        conflict.or(replaced)
+                    }
+                    UnflattenTree::Branch(map) => {
+                        if !map.contains_key(&*key) {
+                            map.insert(key.to_string(), UnflattenTree::Empty);
+                        }
+                        map.get_mut(&*key).unwrap().insert(keys, value)
+                    }
+                    UnflattenTree::Array(_) => unreachable!(),
                 }
-                UnflattenTree::Branch(map) => {
-                    if !map.contains_key(key) {
-                        map.insert(key.to_string(), UnflattenTree::Empty);
+            }
+            Some(Segment::Index(i)) => match self {
+                UnflattenTree::Branch(map) => map
+                    .entry(i.to_string())
+                    .or_insert(UnflattenTree::Empty)
+                    .insert(keys, value),
+                UnflattenTree::Array(items) => {
+                    if items.len() <= i {
+                        items.resize(i + 1, UnflattenTree::Empty);
                     }
-                    map.get_mut(key).unwrap().insert(keys, value);
+                    items[i].insert(keys, value)
+                }
+                UnflattenTree::Empty | UnflattenTree::Leaf(_) => {
+                    let replaced = self.replaced_leaf();
+                    let mut items = vec![UnflattenTree::Empty; i + 1];
+                    let conflict = items[i].insert(keys, value);
+                    *self = UnflattenTree::Array(items);
+                    conflict.or(replaced)
+                }
+            },
+            None => match self {
+                tree if tree.has_children() => Some(Conflict::Nested),
+                UnflattenTree::Leaf(old) if *old != value => {
+                    *old = value;
+                    Some(Conflict::Duplicate)
+                }
+                _ => {
+                    *self = UnflattenTree::Leaf(value);
+                    None
+                }
+            },
+        }
+    }
+
+    /// The conflict caused by turning this node into a branch.
+    fn replaced_leaf(&self) -> Option<Conflict> {
+        match self {
+            UnflattenTree::Leaf(_) => Some(Conflict::Nested),
+            _ => None,
+        }
+    }
+}
+
+/// Insert `value` into `map`, merging it with an existing object under the same key.
+fn merge(map: &mut Map<String, Value>, key: String, value: Value) -> Result<()> {
+    match (map.get_mut(&key), value) {
+        (None, value) => {
+            map.insert(key, value);
+        }
+        (Some(Value::Object(existing)), Value::Object(new)) => {
+            for (k, v) in new {
+                merge(existing, k, v)?;
+            }
+        }
+        (Some(_), _) => bail!("key {:?} is defined more than once", key),
+    }
+    Ok(())
+}
+
+/// Parse a string written by `--stringify-deep`, leaving it as it is if it isn't a JSON object or
+/// array.
+fn parse_leaf(s: String) -> Value {
+    match serde_json::from_str::<Value>(&s) {
+        Ok(v) if v.is_object() || v.is_array() => v,
+        _ => Value::String(s),
+    }
+}
+
+fn is_empty_container(value: &Value) -> bool {
+    match value {
+        Value::Array(items) => items.is_empty(),
+        Value::Object(items) => items.is_empty(),
+        _ => false,
+    }
+}
+
+/// Somewhere flattened key/value pairs are put.
+trait FlatOutput {
+    /// Add a key/value pair, replacing the value if the key was already there.  Returns `true` if
+    /// the key was already there.
+    fn insert(&mut self, key: &str, value: Value) -> Result<bool>;
+}
+
+impl FlatOutput for IndexMap<String, Value> {
+    fn insert(&mut self, key: &str, value: Value) -> Result<bool> {
+        Ok(IndexMap::insert(self, key.to_string(), value).is_some())
+    }
+}
+
+/// Serializes key/value pairs straight to the output, see `--low-memory`.
+struct StreamMap<M> {
+    map: M,
+    /// The keys written so far, to find keys which occur more than once (only with --strict)
+    seen: Option<HashSet<String>>,
+}
+
+impl<M> FlatOutput for StreamMap<M>
+where
+    M: SerializeMap,
+    M::Error: Send + Sync + 'static,
+{
+    fn insert(&mut self, key: &str, value: Value) -> Result<bool> {
+        self.map.serialize_entry(key, &value)?;
+        Ok(match &mut self.seen {
+            Some(seen) => !seen.insert(key.to_string()),
+            None => false,
+        })
+    }
+}
+
+/// Writes a key segment to the wrapped buffer as a JSON Pointer reference token, escaping `~` and
+/// `/`.
+struct PointerToken<'a>(&'a mut String);
+
+impl Write for PointerToken<'_> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        for c in s.chars() {
+            match c {
+                '~' => self.0.push_str("~0"),
+                '/' => self.0.push_str("~1"),
+                c => self.0.push(c),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes a key segment to the wrapped buffer, escaping it if needed.
+struct EscapeKey<'a>(&'a Flatten, &'a mut String);
+
+impl Write for EscapeKey<'_> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        let EscapeKey(options, buf) = self;
+        if options.pointer {
+            return PointerToken(buf).write_str(s);
+        }
+        let escape = match options.escape {
+            Some(e) => e,
+            None => {
+                buf.push_str(s);
+                return Ok(());
+            }
+        };
+        let sep = &*options.sep;
+        let mut rest = s;
+        while let Some(c) = rest.chars().next() {
+            if !sep.is_empty() && rest.starts_with(sep) {
+                buf.push(escape);
+                buf.push_str(sep);
+                rest = &rest[sep.len()..];
+            } else {
+                if c == escape || (c == '[' && options.brackets) || (c == '"' && options.fidelity) {
+                    buf.push(escape);
                 }
+                buf.push(c);
+                rest = &rest[c.len_utf8()..];
             }
-        } else if !self.has_children() {
-            *self = UnflattenTree::Leaf(value);
         }
+        Ok(())
     }
 }
 
 impl Flatten {
-    fn recurse<I, K>(
+    /// Insert the leaf `value` into `output` at `key`, taking it out of the record and leaving
+    /// `true` in its place (see `flatten`).  Returns `true` if `key` was already in `output`.
+    fn insert(&self, output: &mut impl FlatOutput, key: &str, value: &mut Value) -> Result<bool> {
+        if self.drop_nulls && value.is_null() {
+            return Ok(false);
+        }
+        if !self.include.is_empty() && !self.include.iter().any(|r| r.is_match(key)) {
+            return Ok(false);
+        }
+        if self.exclude.iter().any(|r| r.is_match(key)) {
+            return Ok(false);
+        }
+        let mut value = std::mem::replace(value, Value::Bool(true));
+        if self.stringify_deep && (value.is_object() || value.is_array()) {
+            value = Value::String(value.to_string());
+        }
+        output.insert(key, value)
+    }
+
+    /// Flatten each of `items` into `leaf`, see `flatten_into`.  `index` should be `true` if the
+    /// items are array elements.  `depth` is the number of levels of nesting above the items.
+    fn recurse<'v, I, K, F>(
         self: &Flatten,
-        output: &mut IndexMap<String, Value>,
-        current_key: String,
+        current_key: &mut String,
+        mut source: Option<&mut String>,
         items: I,
-    ) where
+        index: bool,
+        depth: usize,
+        leaf: &mut F,
+    ) -> Result<()>
+    where
         K: Display,
-        I: IntoIterator<Item = (K, Value)>,
+        I: IntoIterator<Item = (K, &'v mut Value)>,
+        F: FnMut(&str, Option<&str>, &mut Value) -> Result<()>,
     {
+        let len = current_key.len();
+        let source_len = source.as_ref().map_or(0, |s| s.len());
         for (k, val) in items {
-            let mut key = current_key.clone();
-            if key.len() == 0 {
-                write!(key, "{}", k).unwrap();
+            if let Some(source) = source.as_deref_mut() {
+                source.push('/');
+                write!(PointerToken(source), "{}", k).unwrap();
+            }
+            self.push_key(current_key, k, index);
+            if !self.drop_empty && is_empty_container(val) {
+                leaf(current_key, source.as_deref().map(String::as_str), val)?;
             } else {
-                write!(key, "{}{}", &self.sep, k).unwrap();
+                self.flatten_into(current_key, source.as_deref_mut(), val, depth + 1, leaf)?;
+            }
+            current_key.truncate(len);
+            if let Some(source) = source.as_deref_mut() {
+                source.truncate(source_len);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the key or array index `k` to the end of the flattened key `current_key`.
+    fn push_key(&self, current_key: &mut String, k: impl Display, index: bool) {
+        if index && self.brackets {
+            write!(current_key, "[{}]", k).unwrap();
+        } else {
+            if self.pointer {
+                current_key.push('/');
+            } else if !current_key.is_empty() {
+                current_key.push_str(&self.sep);
+            }
+            let start = current_key.len();
+            match self.key_case {
+                Some(case) if !index => {
+                    write!(
+                        EscapeKey(self, current_key),
+                        "{}",
+                        case.apply(&k.to_string())
+                    )
+                }
+                _ => write!(EscapeKey(self, current_key), "{}", k),
+            }
+            .unwrap();
+            if self.fidelity && current_key.len() == start {
+                current_key.push_str("\"\"");
+            }
+        }
+    }
+
+    /// Take the value at `--root` out of `value`, adding the path to it to `key` with
+    /// --absolute-keys.  Returns `None` if there's no value there and the record should be
+    /// skipped.
+    fn select_root(&self, mut value: Value, root: &str, key: &mut String) -> Result<Option<Value>> {
+        for token in root.split('/').skip(1) {
+            let token = unescape_pointer(token);
+            let (next, index) = match &mut value {
+                Value::Object(map) => (map.remove(&*token), false),
+                Value::Array(items) => {
+                    let i = parse_index(&token).filter(|&i| i < items.len());
+                    (i.map(|i| items.swap_remove(i)), true)
+                }
+                _ => (None, false),
+            };
+            value = match next {
+                Some(next) => next,
+                None if self.strict => bail!("record has no value at {:?}", root),
+                None => {
+                    eprintln!("warning: skipping a record with no value at {:?}", root);
+                    return Ok(None);
+                }
+            };
+            if self.absolute_keys {
+                self.push_key(key, token, index);
+            }
+        }
+        Ok(Some(value))
+    }
+
+    /// Nest an unflattened record under `--root`, unless its keys are absolute.
+    fn nest_under_root(&self, mut tree: UnflattenTree) -> UnflattenTree {
+        let root = match &self.root {
+            Some(root) if !self.absolute_keys => root,
+            _ => return tree,
+        };
+        if let Some(tokens) = root.strip_prefix('/') {
+            for token in tokens.rsplit('/') {
+                let key = unescape_pointer(token).into_owned();
+                tree = UnflattenTree::Branch(IndexMap::from_iter([(key, tree)]));
             }
-            self.flatten(output, key, val);
         }
+        tree
     }
 
+    /// Flatten the record `current_value` into `output`, starting every key with `current_key`
+    /// (which is used as a buffer for building keys, and is left unchanged).  Keys which come from
+    /// more than one path in the record are reported, see `report_collision`.
     fn flatten(
         &self,
-        output: &mut IndexMap<String, Value>,
-        current_key: String,
-        current_value: Value,
-    ) {
+        output: &mut impl FlatOutput,
+        current_key: &mut String,
+        mut current_value: Value,
+        depth: usize,
+    ) -> Result<()> {
+        let mut collisions = Vec::new();
+        self.flatten_into(
+            current_key,
+            None,
+            &mut current_value,
+            depth,
+            &mut |key, _, value| {
+                if self.insert(output, key, value)? && !collisions.iter().any(|k| k == key) {
+                    collisions.push(key.to_string());
+                }
+                Ok(())
+            },
+        )?;
+        for key in collisions {
+            self.report_collision(&mut current_value, current_key, depth, &key)?;
+        }
+        Ok(())
+    }
+
+    /// Report that more than one path in `record` was flattened to `key`, where `record` is what's
+    /// left of a record after `flatten`.  This walks the record again to find the paths, so that
+    /// they don't have to be tracked for records where nothing collides.
+    fn report_collision(
+        &self,
+        record: &mut Value,
+        current_key: &mut String,
+        depth: usize,
+        key: &str,
+    ) -> Result<()> {
+        let mut sources = Vec::new();
+        self.flatten_into(
+            current_key,
+            Some(&mut String::new()),
+            record,
+            depth,
+            &mut |k, source, value| {
+                // Leaves dropped by --drop-nulls are still null, everything inserted is `true`
+                if k == key && !(self.drop_nulls && value.is_null()) {
+                    sources.extend(source.map(String::from));
+                }
+                Ok(())
+            },
+        )?;
+        let hint = match self.key_case {
+            None if self.escape.is_none() && !self.pointer => " (use --escape to avoid this)",
+            _ => "",
+        };
+        let (paths, kept) = match sources.split_last() {
+            Some((last, [first])) => (format!("both {} and {}", first, last), last),
+            Some((last, rest)) if !rest.is_empty() => {
+                (format!("{} and {}", rest.join(", "), last), last)
+            }
+            _ => bail!("key {:?} occurs more than once after flattening", key),
+        };
+        if self.strict {
+            bail!(
+                "key {:?} comes from {} after flattening{}",
+                key,
+                paths,
+                hint
+            );
+        }
+        eprintln!(
+            "warning: key {:?} comes from {} after flattening, keeping the value from {}{}",
+            key, paths, kept, hint
+        );
+        Ok(())
+    }
+
+    /// Call `leaf` with the flattened key of each leaf of `current_value` (starting with
+    /// `current_key`), its path as a JSON Pointer if `source` is given (starting with `source`),
+    /// and the leaf itself.  `depth` is the number of levels of nesting above `current_value`;
+    /// values at `--max-depth` are leaves.
+    fn flatten_into<F>(
+        &self,
+        current_key: &mut String,
+        source: Option<&mut String>,
+        current_value: &mut Value,
+        depth: usize,
+        leaf: &mut F,
+    ) -> Result<()>
+    where
+        F: FnMut(&str, Option<&str>, &mut Value) -> Result<()>,
+    {
+        if matches!(self.max_depth, Some(max) if depth >= max) {
+            return leaf(
+                current_key,
+                source.as_deref().map(String::as_str),
+                current_value,
+            );
+        }
         match current_value {
-            Value::Array(items) => self.recurse(output, current_key, items.into_iter().enumerate()),
-            Value::Object(items) => self.recurse(output, current_key, items),
+            Value::Array(items) if !self.keep_arrays => {
+                let len = items.len();
+                let truncated = matches!(self.max_array_len, Some(max) if len > max);
+                if truncated && self.max_array_len_drop {
+                    return Ok(());
+                }
+                let mut source = source;
+                let max = self.max_array_len.unwrap_or(len);
+                let items = items.iter_mut().enumerate().take(max);
+                self.recurse(current_key, source.as_deref_mut(), items, true, depth, leaf)?;
+                if truncated {
+                    let marker = [("__truncated", &mut Value::from(len))];
+                    self.recurse(current_key, source, marker, false, depth, leaf)?;
+                }
+                Ok(())
+            }
+            Value::Object(items) => {
+                self.recurse(current_key, source, items.iter_mut(), false, depth, leaf)
+            }
+            leaf_value => leaf(
+                current_key,
+                source.as_deref().map(String::as_str),
+                leaf_value,
+            ),
+        }
+    }
+
+    /// Find a key in `value` which contains the separator, among the keys which are flattened.
+    fn find_key_with_sep<'a>(&self, value: &'a Value, depth: usize) -> Option<&'a str> {
+        if matches!(self.max_depth, Some(max) if depth >= max) {
+            return None;
+        }
+        match value {
+            Value::Object(map) => map.iter().find_map(|(k, v)| {
+                if k.contains(&*self.sep) {
+                    Some(k.as_str())
+                } else {
+                    self.find_key_with_sep(v, depth + 1)
+                }
+            }),
+            Value::Array(items) if !self.keep_arrays => items
+                .iter()
+                .find_map(|v| self.find_key_with_sep(v, depth + 1)),
+            _ => None,
+        }
+    }
+
+    /// Recursively split keys containing the separator into nested objects.
+    fn split_dotted_keys(&self, value: Value) -> Result<Value> {
+        match value {
+            Value::Object(map) => {
+                let mut result = Map::new();
+                for (key, value) in map {
+                    let mut value = self.split_dotted_keys(value)?;
+                    let key = match key.split_once(&*self.sep) {
+                        Some((head, tail)) if !self.sep.is_empty() => {
+                            for part in tail.rsplit(&*self.sep) {
+                                value = Value::Object(Map::from_iter([(part.to_string(), value)]));
+                            }
+                            head.to_string()
+                        }
+                        _ => key,
+                    };
+                    merge(&mut result, key, value)?;
+                }
+                Ok(Value::Object(result))
+            }
+            Value::Array(items) => items
+                .into_iter()
+                .map(|v| self.split_dotted_keys(v))
+                .collect::<Result<_>>()
+                .map(Value::Array),
+            scalar => Ok(scalar),
+        }
+    }
+
+    /// Replace the array at `path` in `record` with each of its elements in turn.
+    fn explode_one(&self, mut record: Value, path: &[&str]) -> Vec<Value> {
+        let items = match record.get_path_mut(path.iter().copied()) {
+            Some(Value::Array(items)) if !items.is_empty() => std::mem::take(items),
+            Some(Value::Array(_)) | None => Vec::new(),
+            Some(_) => return vec![record],
+        };
+        if items.is_empty() {
+            if self.explode_drop_missing {
+                return Vec::new();
+            }
+            let (last, parent) = path.split_last().unwrap();
+            if let Some(Value::Object(map)) = record.get_path_mut(parent.iter().copied()) {
+                *map = std::mem::take(map)
+                    .into_iter()
+                    .filter(|(k, _)| k != last)
+                    .collect();
+            }
+            return vec![record];
+        }
+
+        let mut records = Vec::with_capacity(items.len());
+        let mut items = items.into_iter().peekable();
+        while let Some(item) = items.next() {
+            let mut exploded = if items.peek().is_some() {
+                record.clone()
+            } else {
+                std::mem::take(&mut record)
+            };
+            *exploded.get_path_mut(path.iter().copied()).unwrap() = item;
+            records.push(exploded);
+        }
+        records
+    }
+
+    /// Remove `--strip-prefix` from `key`.  Returns `None` if the key doesn't have the prefix.
+    fn remove_prefix<'a>(&self, key: &'a str) -> Option<&'a str> {
+        let prefix = match &self.strip_prefix {
+            Some(prefix) => prefix,
+            None => return Some(key),
+        };
+        let rest = key.strip_prefix(&**prefix)?;
+        if self.pointer {
+            // Keep the `/` which starts the rest of the pointer
+            if rest.is_empty() || rest.starts_with('/') {
+                Some(rest)
+            } else {
+                None
+            }
+        } else if let Some(re) = &self.sep_regex {
+            match re.find(rest) {
+                Some(m) if m.start() == 0 => Some(&rest[m.end()..]),
+                _ if rest.is_empty() || (self.brackets && rest.starts_with('[')) => Some(rest),
+                _ => None,
+            }
+        } else if let Some(rest) = rest.strip_prefix(&*self.sep) {
+            Some(rest)
+        } else if rest.is_empty() || (self.brackets && rest.starts_with('[')) {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+
+    /// The policy for gaps in array indices, see `--sparse`.
+    fn sparse(&self) -> Sparse {
+        match self.sparse {
+            Some(sparse) => sparse,
+            None if self.fill_gaps => Sparse::Null,
+            None => Sparse::Error,
+        }
+    }
+
+    /// With --regex-sep, compile the separator.  Regexes which match the empty string are rejected,
+    /// since they would split keys between every character.
+    fn compile_regex_sep(&mut self) -> Result<()> {
+        if !self.regex_sep {
+            return Ok(());
+        }
+        let re = Regex::new(&self.sep)
+            .with_context(|| format!("invalid separator regex {:?}", self.sep))?;
+        if re.is_match("") {
+            bail!("separator regex {:?} matches the empty string", self.sep);
+        }
+        self.sep_regex = Some(re);
+        Ok(())
+    }
 
-            scalar => {
-                output.insert(current_key, scalar);
+    /// Split a flattened key into its components.
+    fn segments<'a>(&self, key: &'a str) -> Vec<Segment<'a>> {
+        let key = self.remove_prefix(key).unwrap_or(key);
+        if self.pointer {
+            return match key.strip_prefix('/') {
+                Some(key) => key
+                    .split('/')
+                    .map(|token| Segment::Key(unescape_pointer(token)))
+                    .collect(),
+                None => Vec::new(),
+            };
+        }
+        let parts = match (self.escape, &self.sep_regex) {
+            (Some(e), _) => split_unescaped(key, &self.sep, e),
+            (None, Some(re)) => re.split(key).collect(),
+            (None, None) => key.split(&*self.sep).collect(),
+        };
+        if self.brackets {
+            let mut segments = Vec::new();
+            for part in parts {
+                parse_brackets(part, self.escape, self.fidelity, &mut segments);
             }
+            segments
+        } else {
+            parts
+                .into_iter()
+                .map(|part| Segment::Key(unescape(part, self.escape)))
+                .collect()
         }
     }
 
+    /// Find the key in `keys` which conflicts with `segments`, ie. one is a prefix of the other.
+    fn find_conflict<'a>(&self, keys: &'a [String], segments: &[Segment]) -> Option<&'a str> {
+        let same = |a: &Segment, b: &Segment| match (a, b) {
+            (Segment::Key(k), Segment::Index(i)) | (Segment::Index(i), Segment::Key(k)) => {
+                *k == i.to_string()
+            }
+            _ => a == b,
+        };
+        keys.iter().map(String::as_str).find(|k| {
+            self.segments(k)
+                .iter()
+                .zip(segments)
+                .all(|(a, b)| same(a, b))
+        })
+    }
+
     fn unflatten(&self, input: Value) -> Result<UnflattenTree> {
         let input = match input {
             Value::Object(x) => x,
+            other if self.fidelity || self.lenient => return Ok(UnflattenTree::Leaf(other)),
             _ => bail!("top-level object must be to be object type"),
         };
+        if self.fidelity && input.is_empty() {
+            return Ok(UnflattenTree::Branch(IndexMap::new()));
+        }
         let mut tree = UnflattenTree::Empty;
+        self.insert_record(&mut tree, &mut Vec::new(), input)?;
+        if self.arrays {
+            tree.rebuild_arrays(self.sparse())?;
+        }
+        Ok(self.nest_under_root(tree))
+    }
 
+    /// Insert each key of the flat record `input` into `tree`.  `seen` holds the keys inserted so
+    /// far, to report conflicts in strict mode.
+    fn insert_record(
+        &self,
+        tree: &mut UnflattenTree,
+        seen: &mut Vec<String>,
+        input: Map<String, Value>,
+    ) -> Result<()> {
         for (key, value) in input {
-            tree.insert(key.split(&*self.sep), value);
+            if !self.lenient && self.remove_prefix(&key).is_none() {
+                bail!(
+                    "key {:?} doesn't start with the prefix {:?}",
+                    key,
+                    self.strip_prefix.as_deref().unwrap_or_default()
+                );
+            }
+            if self.pointer && !is_pointer(self.remove_prefix(&key).unwrap_or(&key)) {
+                bail!("key {:?} isn't a JSON Pointer", key);
+            }
+            let value = match value {
+                Value::String(s) if self.parse_leaves => parse_leaf(s),
+                value => value,
+            };
+            let segments = self.segments(&key);
+            let conflict = tree.insert(segments.iter().cloned(), value);
+            if let Some(conflict) = conflict.filter(|_| self.strict) {
+                let other = match self.find_conflict(seen, &segments) {
+                    Some(other) => other,
+                    None => bail!("key {:?} conflicts with another key", key),
+                };
+                match conflict {
+                    Conflict::Nested => {
+                        let parent = if self.segments(other).len() < segments.len() {
+                            other
+                        } else {
+                            &key
+                        };
+                        bail!(
+                            "keys {:?} and {:?} conflict: {:?} has a value, so no keys can be \
+                             nested inside it",
+                            other,
+                            key,
+                            parent
+                        )
+                    }
+                    Conflict::Duplicate => bail!(
+                        "keys {:?} and {:?} conflict: they have different values for the same path",
+                        other,
+                        key
+                    ),
+                }
+            }
+            if self.strict {
+                seen.push(key);
+            }
         }
-
-        Ok(tree)
+        Ok(())
     }
 }
 
 impl RunStreamJson for Flatten {
-    fn process_one<S>(&mut self, value: Value, output: S) -> Result<()>
-    where
-        S: serde::Serializer,
-        S::Error: Send + Sync + 'static,
-    {
-        if value.is_object() || value.is_array() {
-            let mut flat = IndexMap::new();
-            self.flatten(&mut flat, String::new(), value);
-            flat.serialize(output)?;
-        } else {
-            value.serialize(output)?;
+    fn split_record(&mut self, value: Value) -> Result<Vec<Value>> {
+        let mut records = vec![value];
+        for path in &self.explode {
+            let path: Vec<_> = path.split(&*self.sep).collect();
+            records = records
+                .into_iter()
+                .flat_map(|r| self.explode_one(r, &path))
+                .collect();
         }
-        Ok(())
+        Ok(records)
     }
-}
-
-struct Unflatten(Flatten);
 
-impl RunStreamJson for Unflatten {
-    fn process_one<S>(&mut self, value: Value, output: S) -> Result<()>
+    fn process_one<S>(&mut self, mut value: Value, output: S) -> Result<Option<S::Ok>>
     where
         S: serde::Serializer,
         S::Error: Send + Sync + 'static,
     {
-        let value = self.0.unflatten(value)?;
-        value.serialize(output)?;
-        Ok(())
+        let mut key = self.prefix.clone().unwrap_or_default();
+        if let Some(root) = &self.root {
+            value = match self.select_root(value, root, &mut key)? {
+                Some(value) => value,
+                None => return Ok(None),
+            };
+        }
+        if self.toml_dotted_keys {
+            value = self.split_dotted_keys(value)?;
+        }
+        let flatten = match &value {
+            Value::Object(_) => true,
+            Value::Array(items) => !(self.keep_arrays || (self.fidelity && items.is_empty())),
+            _ => false,
+        };
+        // With --absolute-keys, a root which isn't flattened is still written under its key
+        if (flatten && self.max_depth != Some(0)) || (self.absolute_keys && !key.is_empty()) {
+            if self.strict && self.escape.is_none() && !self.pointer && !self.sep.is_empty() {
+                if let Some(key) = self.find_key_with_sep(&value, 0) {
+                    bail!(
+                        "key {:?} contains the separator {:?}, use another separator with -d or \
+                         escape it with --escape",
+                        key,
+                        self.sep
+                    );
+                }
+            }
+            if self.low_memory {
+                let mut map = StreamMap {
+                    map: output.serialize_map(None)?,
+                    seen: self.strict.then(HashSet::new),
+                };
+                self.flatten(&mut map, &mut key, value, 0)?;
+                return Ok(Some(map.map.end()?));
+            }
+            let mut flat = std::mem::take(&mut self.flat);
+            self.flatten(&mut flat, &mut key, value, 0)?;
+            let ok = flat.serialize(output)?;
+            flat.clear();
+            self.flat = flat;
+            Ok(Some(ok))
+        } else {
+            Ok(Some(value.serialize(output)?))
+        }
     }
 }
 
-fn main() -> Result<()> {
-    reset_sigpipe();
-    let mut args = Args::parse();
-    let input = Input::default_stdin(args.input.as_ref())?;
-    if args.unflatten {
-        Unflatten(args.options).main(input)
+/// Write `s` to `buf` as a CSV field, in double quotes if it contains a comma, double quote or
+/// line break.  Double quotes inside the field are doubled.
+fn escape_csv(buf: &mut String, s: &str) {
+    if s.contains([',', '"', '\n', '\r']) {
+        buf.push('"');
+        buf.push_str(&s.replace('"', "\"\""));
+        buf.push('"');
     } else {
-        args.options.main(input)
+        buf.push_str(s);
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use serde_json::json;
-
-    use super::*;
+/// Write a flattened value to `buf` as a CSV field, see `--format csv`.
+fn push_csv_value(buf: &mut String, value: Value) {
+    match value {
+        Value::String(s) => escape_csv(buf, &s),
+        Value::Null => {}
+        Value::Bool(b) => buf.push(if b { '1' } else { '0' }),
+        other => escape_csv(buf, &other.to_string()),
+    }
+}
 
-    fn options() -> Flatten {
-        Flatten {
-            sep: ".".to_string(),
+/// Write `s` to `buf`, escaping `\`, `=` and newlines for the `kv` format.
+fn escape_kv(buf: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '\\' => buf.push_str("\\\\"),
+            '=' => buf.push_str("\\="),
+            '\n' => buf.push_str("\\n"),
+            c => buf.push(c),
         }
     }
+}
 
-    fn unflatten(value: Value) -> Value {
-        let u = options().unflatten(value).unwrap();
+/// Split a `kv` line on the first unescaped `=`, unescaping the key and value.
+fn split_kv(line: &str) -> Option<(String, String)> {
+    let mut key = String::new();
+    let mut value = String::new();
+    let mut in_key = true;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        let buf = if in_key { &mut key } else { &mut value };
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => buf.push('\n'),
+                Some(c) => buf.push(c),
+                None => buf.push('\\'),
+            },
+            '=' if in_key => in_key = false,
+            c => buf.push(c),
+        }
+    }
+    if in_key {
+        None
+    } else {
+        Some((key, value))
+    }
+}
+
+impl Flatten {
+    /// Flatten `value` and write it to `buf` as `key=value` lines, with a blank line between the
+    /// records it is exploded into.  Lines are sorted by key if `order` is given.
+    fn write_kv(&mut self, value: Value, order: Option<SortKeys>, buf: &mut String) -> Result<()> {
+        for (i, value) in self.split_record(value)?.into_iter().enumerate() {
+            if i > 0 {
+                buf.push('\n');
+            }
+            self.write_kv_record(value, order, buf)?;
+        }
+        Ok(())
+    }
+
+    fn write_kv_record(
+        &mut self,
+        value: Value,
+        order: Option<SortKeys>,
+        buf: &mut String,
+    ) -> Result<()> {
+        for (key, value) in self.flat_pairs(value, order, "key=value lines")? {
+            escape_kv(buf, &key);
+            buf.push('=');
+            match value {
+                Value::String(s) => escape_kv(buf, &s),
+                Value::Null => {}
+                other => escape_kv(buf, &other.to_string()),
+            }
+            buf.push('\n');
+        }
+        Ok(())
+    }
+
+    /// Flatten a single record to its key/value pairs, sorted by key if `order` is given.
+    /// `format` describes the output for errors about records which can't be flattened.
+    fn flat_pairs(
+        &mut self,
+        value: Value,
+        order: Option<SortKeys>,
+        format: &str,
+    ) -> Result<Vec<(String, Value)>> {
+        let mut flat: Vec<_> = match self.process_one(value, serde_json::value::Serializer)? {
+            Some(Value::Object(flat)) => flat.into_iter().collect(),
+            Some(other) => bail!("can't write {} as {}", other.type_name(), format),
+            None => return Ok(Vec::new()),
+        };
+        match order {
+            Some(SortKeys::Lexical) => flat.sort_by(|(a, _), (b, _)| a.cmp(b)),
+            Some(SortKeys::Natural) => flat.sort_by(|(a, _), (b, _)| natural_cmp(a, b)),
+            None => {}
+        }
+        Ok(flat)
+    }
+
+    /// Flatten `value` and write it to `buf` as `key,value` CSV rows, one for each record it is
+    /// exploded into.  `index` is the index of the next record, see `--record-index`.
+    fn write_csv(
+        &mut self,
+        value: Value,
+        order: Option<SortKeys>,
+        index: &mut usize,
+        buf: &mut String,
+    ) -> Result<()> {
+        for value in self.split_record(value)? {
+            for (key, value) in self.flat_pairs(value, order, "CSV rows")? {
+                escape_csv(buf, &key);
+                buf.push(',');
+                push_csv_value(buf, value);
+                if self.record_index {
+                    write!(buf, ",{}", index).unwrap();
+                }
+                buf.push('\n');
+            }
+            *index += 1;
+        }
+        Ok(())
+    }
+
+    /// Parse a `kv` value, see `--string-values`.
+    fn parse_kv_value(&self, value: String) -> Value {
+        if self.string_values {
+            return Value::String(value);
+        }
+        if value.is_empty() {
+            return Value::Null;
+        }
+        match serde_json::from_str::<Value>(&value) {
+            Ok(v) if !v.is_string() => v,
+            _ => Value::String(value),
+        }
+    }
+
+    /// Read `key=value` records from `input`, calling `f` with each one.
+    fn read_kv(
+        &self,
+        input: impl BufRead,
+        mut f: impl FnMut(Map<String, Value>) -> Result<()>,
+    ) -> Result<()> {
+        let mut record = Map::new();
+        for (n, line) in input.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                if !record.is_empty() {
+                    f(std::mem::take(&mut record))?;
+                }
+                continue;
+            }
+            let (key, value) =
+                split_kv(&line).with_context(|| format!("line {}: expected `key=value`", n + 1))?;
+            record.insert(key, self.parse_kv_value(value));
+        }
+        if !record.is_empty() {
+            f(record)?;
+        }
+        Ok(())
+    }
+}
+
+/// Call `f` with the index of each JSON record in the input and the record.  Returns the number
+/// of records.
+fn for_each_record(
+    input: &InputOptions,
+    mut f: impl FnMut(usize, Value) -> Result<()>,
+) -> Result<usize> {
+    let mut count = 0;
+    input.for_each(|input| {
+        let input: Box<dyn io::Read> = match input {
+            Input::File(f) => Box::new(BufReader::new(f)),
+            Input::Stdin(i) => Box::new(i.lock()),
+        };
+        for value in serde_json::Deserializer::from_reader(input).into_iter::<Value>() {
+            f(count, value?).with_context(|| format!("failed to process record {}", count))?;
+            count += 1;
+        }
+        Ok(())
+    })?;
+    Ok(count)
+}
+
+/// Flatten each record to `kv` lines, returning the number of records.
+fn flatten_kv(
+    options: &mut Flatten,
+    input: &InputOptions,
+    order: Option<SortKeys>,
+    output: &mut dyn io::Write,
+) -> Result<usize> {
+    let mut buf = String::new();
+    for_each_record(input, |i, value| {
+        buf.clear();
+        if i > 0 {
+            buf.push('\n');
+        }
+        options.write_kv(value, order, &mut buf)?;
+        output.write_all(buf.as_bytes())?;
+        Ok(())
+    })
+}
+
+impl Flatten {
+    /// The lines `--keys-only` writes for `value`: each flattened key, followed by a tab and the
+    /// type of its value with `--with-types`.  Records which aren't flattened have no keys.
+    fn key_lines(&mut self, value: Value) -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        for value in self.split_record(value)? {
+            if let Some(Value::Object(flat)) =
+                self.process_one(value, serde_json::value::Serializer)?
+            {
+                lines.extend(flat.into_iter().map(|(key, value)| {
+                    if self.with_types {
+                        format!("{}\t{}", key, value.type_name())
+                    } else {
+                        key
+                    }
+                }));
+            }
+        }
+        Ok(lines)
+    }
+}
+
+/// Write `lines`, sorted if `order` is given.
+fn write_lines(
+    mut lines: Vec<String>,
+    order: Option<SortKeys>,
+    output: &mut dyn io::Write,
+) -> Result<()> {
+    match order {
+        Some(SortKeys::Lexical) => lines.sort(),
+        Some(SortKeys::Natural) => lines.sort_by(|a, b| natural_cmp(a, b)),
+        None => {}
+    }
+    for line in lines {
+        output.write_all(line.as_bytes())?;
+        output.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Write the flattened keys of each record, or with `--unique`, every distinct key once at the
+/// end.  Returns the number of records.
+fn flatten_keys(
+    options: &mut Flatten,
+    input: &InputOptions,
+    order: Option<SortKeys>,
+    output: &mut dyn io::Write,
+) -> Result<usize> {
+    let mut seen = IndexSet::new();
+    let count = for_each_record(input, |_, value| {
+        let lines = options.key_lines(value)?;
+        if options.unique {
+            seen.extend(lines);
+            Ok(())
+        } else {
+            write_lines(lines, order, output)
+        }
+    })?;
+    if options.unique {
+        write_lines(seen.into_iter().collect(), order, output)?;
+    }
+    Ok(count)
+}
+
+/// Flatten each record to CSV rows after a header, returning the number of records.
+fn flatten_csv(
+    options: &mut Flatten,
+    input: &InputOptions,
+    order: Option<SortKeys>,
+    output: &mut dyn io::Write,
+) -> Result<usize> {
+    let mut buf = String::from("key,value");
+    if options.record_index {
+        buf.push_str(",record");
+    }
+    buf.push('\n');
+    output.write_all(buf.as_bytes())?;
+    let mut index = 0;
+    for_each_record(input, |_, value| {
+        buf.clear();
+        options.write_csv(value, order, &mut index, &mut buf)?;
+        output.write_all(buf.as_bytes())?;
+        Ok(())
+    })
+}
+
+/// Flatten every record into a row of a CSV table, see `--table`.  Returns the number of records.
+fn flatten_table(
+    options: &mut Flatten,
+    input: &InputOptions,
+    order: Option<SortKeys>,
+    output: &mut dyn io::Write,
+) -> Result<usize> {
+    let mut columns = IndexSet::new();
+    let mut rows = Vec::new();
+    let count = for_each_record(input, |_, value| {
+        for value in options.split_record(value)? {
+            let mut row = Vec::new();
+            for (key, value) in options.flat_pairs(value, None, "a table row")? {
+                let (i, _) = columns.insert_full(key);
+                if row.len() <= i {
+                    row.resize(i + 1, Value::Null);
+                }
+                row[i] = value;
+            }
+            rows.push(row);
+        }
+        Ok(())
+    })?;
+
+    let mut header: Vec<_> = columns.iter().enumerate().collect();
+    match order {
+        Some(SortKeys::Lexical) => header.sort_by_key(|&(_, key)| key),
+        Some(SortKeys::Natural) => header.sort_by(|(_, a), (_, b)| natural_cmp(a, b)),
+        None => {}
+    }
+    let mut buf = String::new();
+    for (i, (_, key)) in header.iter().enumerate() {
+        if i > 0 {
+            buf.push(',');
+        }
+        escape_csv(&mut buf, key);
+    }
+    buf.push('\n');
+    output.write_all(buf.as_bytes())?;
+    for mut row in rows {
+        buf.clear();
+        for (i, &(column, _)) in header.iter().enumerate() {
+            if i > 0 {
+                buf.push(',');
+            }
+            if let Some(value) = row.get_mut(column) {
+                push_csv_value(&mut buf, value.take());
+            }
+        }
+        buf.push('\n');
+        output.write_all(buf.as_bytes())?;
+    }
+    Ok(count)
+}
+
+/// Unflatten each `kv` record, writing it as JSON.  Returns the number of records.
+fn unflatten_kv(
+    unflatten: &mut Unflatten,
+    input: &InputOptions,
+    output: &OutputOptions,
+    w: &mut dyn io::Write,
+) -> Result<usize> {
+    let mut count = 0;
+    let options = unflatten.options.clone();
+    let mut write = |record| -> Result<()> {
+        let context = || format!("failed to process record {}", count);
+        if options.merge {
+            unflatten
+                .merge(Value::Object(record))
+                .with_context(context)?;
+        } else {
+            let value = options
+                .unflatten(Value::Object(record))
+                .with_context(context)?;
+            output.serialize_to(&mut *w, &value)?;
+            w.write_all(b"\n")?;
+        }
+        count += 1;
+        Ok(())
+    };
+    input.for_each(|input| match input {
+        Input::File(f) => options.read_kv(BufReader::new(f), &mut write),
+        Input::Stdin(i) => options.read_kv(i.lock(), &mut write),
+    })?;
+    if options.merge {
+        output.serialize_to(&mut *w, &unflatten.take_merged()?)?;
+        w.write_all(b"\n")?;
+    }
+    Ok(count)
+}
+
+struct Unflatten {
+    options: Flatten,
+    /// With --merge, the document every record is merged into, and the keys inserted so far
+    merged: UnflattenTree,
+    seen: Vec<String>,
+}
+
+impl Unflatten {
+    fn new(options: Flatten) -> Self {
+        Unflatten {
+            options,
+            merged: UnflattenTree::Empty,
+            seen: Vec::new(),
+        }
+    }
+
+    /// Insert a flat record into the merged document.
+    fn merge(&mut self, value: Value) -> Result<()> {
+        match value {
+            Value::Object(input) => {
+                self.options
+                    .insert_record(&mut self.merged, &mut self.seen, input)
+            }
+            _ => bail!("top-level object must be to be object type"),
+        }
+    }
+
+    /// Take the merged document, which is an empty object if there were no keys.
+    fn take_merged(&mut self) -> Result<UnflattenTree> {
+        let mut tree = match std::mem::replace(&mut self.merged, UnflattenTree::Empty) {
+            UnflattenTree::Empty => UnflattenTree::Branch(IndexMap::new()),
+            tree => tree,
+        };
+        self.seen.clear();
+        if self.options.arrays {
+            tree.rebuild_arrays(self.options.sparse())?;
+        }
+        Ok(self.options.nest_under_root(tree))
+    }
+}
+
+impl RunStreamJson for Unflatten {
+    fn process_one<S>(&mut self, value: Value, output: S) -> Result<Option<S::Ok>>
+    where
+        S: serde::Serializer,
+        S::Error: Send + Sync + 'static,
+    {
+        if self.options.merge && (value.is_object() || !self.options.lenient) {
+            self.merge(value)?;
+            return Ok(None);
+        }
+        let value = self.options.unflatten(value)?;
+        Ok(Some(value.serialize(output)?))
+    }
+
+    fn finish<S>(&mut self, output: S) -> Result<Option<S::Ok>>
+    where
+        S: serde::Serializer,
+        S::Error: Send + Sync + 'static,
+    {
+        if !self.options.merge {
+            return Ok(None);
+        }
+        Ok(Some(self.take_merged()?.serialize(output)?))
+    }
+}
+
+fn main() -> Result<()> {
+    reset_sigpipe();
+    let mut args = Args::parse();
+    if args.options.fidelity {
+        args.options.brackets = true;
+        args.options.escape.get_or_insert('\\');
+    }
+    if args.options.regex_sep && !args.unflatten {
+        bail!("--regex-sep can only be used with -u, since keys can't be joined with a regex");
+    }
+    args.options.compile_regex_sep()?;
+    if matches!(&args.options.root, Some(root) if !is_pointer(root)) {
+        bail!("--root must be a JSON Pointer, eg. `/a/b`");
+    }
+    if args.unflatten && args.options.format == Format::Csv {
+        bail!("the csv format can only be written, not read with -u");
+    }
+    if args.options.table && args.options.format != Format::Json {
+        bail!("--table always writes CSV, so it can't be used with --format");
+    }
+    if args.options.format != Format::Json || args.options.keys_only || args.options.table {
+        let count = args.output.with_output(|w| {
+            if args.options.table {
+                flatten_table(&mut args.options, &args.input, args.output.sort_keys, w)
+            } else if args.options.keys_only {
+                flatten_keys(&mut args.options, &args.input, args.output.sort_keys, w)
+            } else if args.options.format == Format::Csv {
+                flatten_csv(&mut args.options, &args.input, args.output.sort_keys, w)
+            } else if args.unflatten {
+                let mut unflatten = Unflatten::new(args.options.clone());
+                unflatten_kv(&mut unflatten, &args.input, &args.output, w)
+            } else {
+                flatten_kv(&mut args.options, &args.input, args.output.sort_keys, w)
+            }
+        })?;
+        if args.output.count {
+            eprintln!("{}", count);
+        }
+        Ok(())
+    } else if args.unflatten {
+        Unflatten::new(args.options).main(&args.input, &args.output)
+    } else {
+        args.options.main(&args.input, &args.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use super::*;
+
+    fn options() -> Flatten {
+        Flatten {
+            sep: ".".to_string(),
+            brackets: false,
+            keep_arrays: false,
+            drop_empty: false,
+            drop_nulls: false,
+            escape: None,
+            pointer: false,
+            arrays: false,
+            fill_gaps: false,
+            sparse: None,
+            toml_dotted_keys: false,
+            max_depth: None,
+            max_array_len: None,
+            max_array_len_drop: false,
+            stringify_deep: false,
+            parse_leaves: false,
+            strict: false,
+            prefix: None,
+            root: None,
+            absolute_keys: false,
+            strip_prefix: None,
+            lenient: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            format: Format::Json,
+            record_index: false,
+            string_values: false,
+            explode: Vec::new(),
+            explode_drop_missing: false,
+            merge: false,
+            keys_only: false,
+            with_types: false,
+            unique: false,
+            table: false,
+            key_case: None,
+            fidelity: false,
+            regex_sep: false,
+            sep_regex: None,
+            low_memory: false,
+            flat: IndexMap::new(),
+        }
+    }
+
+    fn unflatten(value: Value) -> Value {
+        let u = options().unflatten(value).unwrap();
         let u = serde_json::to_string(&u).unwrap();
         serde_json::from_str(&u).unwrap()
     }
 
     fn flatten(value: Value) -> Value {
         let mut m = IndexMap::new();
-        options().flatten(&mut m, String::new(), value);
+        options()
+            .flatten(&mut m, &mut String::new(), value, 0)
+            .unwrap();
         let out = serde_json::to_string(&m).unwrap();
         serde_json::from_str(&out).unwrap()
     }
@@ -191,6 +1810,31 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn keys_only() -> Result<()> {
+        let mut options = Flatten {
+            keys_only: true,
+            with_types: true,
+            ..options()
+        };
+        let lines = options.key_lines(load_json("tests/recursive.json")?)?;
+        let expected = std::fs::read_to_string("tests/recursive-keys.txt")?;
+        assert_eq!(lines, expected.lines().collect::<Vec<_>>());
+
+        let input = InputOptions {
+            input_dir: Some("tests".into()),
+            glob: Some("?.json".to_string()),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        options.with_types = false;
+        options.unique = true;
+        let count = flatten_keys(&mut options, &input, Some(SortKeys::Lexical), &mut output)?;
+        assert_eq!(count, 2);
+        assert_eq!(String::from_utf8(output)?, "0\n1\n2\nc\nfoo\n");
+        Ok(())
+    }
+
     #[test]
     fn check_unflatten() -> Result<()> {
         let correct = load_json("tests/recursive-flat-unflatten.json")?;
@@ -211,12 +1855,641 @@ mod tests {
         assert_eq!(unflatten(original), unflat);
     }
 
+    #[test]
+    fn regex_separator() -> Result<()> {
+        let mut regex = Flatten {
+            sep: r"\.|__".to_string(),
+            regex_sep: true,
+            strip_prefix: Some("doc".to_string()),
+            ..options()
+        };
+        regex.compile_regex_sep()?;
+        let flat = json!({ "doc.a.b": 1, "doc__a__c": 2, "doc__d__e.f": 3, "doc.g_h": 4 });
+        let unflat = serde_json::to_string(&regex.unflatten(flat)?)?;
+        assert_eq!(unflat, r#"{"a":{"b":1,"c":2},"d":{"e":{"f":3}},"g_h":4}"#);
+
+        for sep in ["_*", "a|", "("] {
+            let mut options = Flatten {
+                sep: sep.to_string(),
+                regex_sep: true,
+                ..options()
+            };
+            assert!(options.compile_regex_sep().is_err(), "{}", sep);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn table() -> Result<()> {
+        let table = |order: Option<SortKeys>| -> Result<String> {
+            let input = InputOptions {
+                path: Some("tests/table.json".into()),
+                ..InputOptions::default()
+            };
+            let mut options = Flatten {
+                table: true,
+                explode: vec!["tags".to_string()],
+                ..options()
+            };
+            let mut output = Vec::new();
+            assert_eq!(flatten_table(&mut options, &input, order, &mut output)?, 4);
+            Ok(String::from_utf8(output)?)
+        };
+        assert_eq!(table(None)?, std::fs::read_to_string("tests/table.csv")?);
+
+        let sorted = table(Some(SortKeys::Lexical))?;
+        assert_eq!(
+            sorted.lines().next(),
+            Some(r#"id,"meta,x",name,size,size.h,size.w,tags,xs.0,xs.1,xs.2"#)
+        );
+        assert_eq!(sorted.lines().nth(1), Some("1,,a,,,2,red,1,,"));
+        Ok(())
+    }
+
+    #[test]
+    fn root() -> Result<()> {
+        let record = json!({ "id": 1, "payload": { "metrics": { "a": 1, "b": [2] }, "n": 3 } });
+        let flatten = |options: Flatten, value: Value| {
+            let mut options = Flatten {
+                root: Some("/payload/metrics".to_string()),
+                ..options
+            };
+            options.process_one(value, serde_json::value::Serializer)
+        };
+
+        let flat = flatten(options(), record.clone())?;
+        assert_eq!(flat, Some(json!({ "a": 1, "b.0": 2 })));
+        let absolute = Flatten {
+            absolute_keys: true,
+            ..brackets()
+        };
+        let flat = flatten(absolute.clone(), record.clone())?;
+        assert_eq!(
+            flat,
+            Some(json!({ "payload.metrics.a": 1, "payload.metrics.b[0]": 2 }))
+        );
+
+        // Missing roots
+        assert_eq!(flatten(options(), json!({ "payload": 5 }))?, None);
+        assert_eq!(flatten(options(), json!({ "id": 2 }))?, None);
+        let strict = Flatten {
+            strict: true,
+            ..options()
+        };
+        assert_eq!(
+            flatten(strict, json!({ "id": 2 })).unwrap_err().to_string(),
+            r#"record has no value at "/payload/metrics""#
+        );
+
+        // Scalar roots
+        let scalar = json!({ "payload": { "metrics": "none" } });
+        assert_eq!(flatten(options(), scalar.clone())?, Some(json!("none")));
+        assert_eq!(
+            flatten(absolute, scalar)?,
+            Some(json!({ "payload.metrics": "none" }))
+        );
+
+        let mut options = Flatten {
+            root: Some("/l/1/a~1b".to_string()),
+            absolute_keys: true,
+            pointer: true,
+            ..options()
+        };
+        let value = json!({ "l": [0, { "a/b": { "c": true } }] });
+        let flat = options.process_one(value, serde_json::value::Serializer)?;
+        assert_eq!(flat, Some(json!({ "/l/1/a~1b/c": true })));
+        Ok(())
+    }
+
+    #[test]
+    fn unflatten_root() -> Result<()> {
+        let options = Flatten {
+            root: Some("/payload/a~1b".to_string()),
+            ..options()
+        };
+        let unflat = options.unflatten(json!({ "x.y": 1, "z": 2 }))?;
+        assert_eq!(
+            serde_json::to_value(unflat)?,
+            json!({ "payload": { "a/b": { "x": { "y": 1 }, "z": 2 } } })
+        );
+
+        let absolute = Flatten {
+            absolute_keys: true,
+            ..options.clone()
+        };
+        let unflat = absolute.unflatten(json!({ "payload.c": 1 }))?;
+        assert_eq!(
+            serde_json::to_value(unflat)?,
+            json!({ "payload": { "c": 1 } })
+        );
+
+        let mut unflatten = Unflatten::new(Flatten {
+            merge: true,
+            ..options
+        });
+        unflatten.merge(json!({ "x": 1 }))?;
+        unflatten.merge(json!({ "y": 2 }))?;
+        assert_eq!(
+            serde_json::to_value(unflatten.take_merged()?)?,
+            json!({ "payload": { "a/b": { "x": 1, "y": 2 } } })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn csv_format() -> Result<()> {
+        let mut options = Flatten {
+            format: Format::Csv,
+            explode: vec!["l".to_string()],
+            record_index: true,
+            ..options()
+        };
+        let value = json!({
+            "a,b": "x, \"y\"",
+            "c": { "d\ne": "line 1\nline 2" },
+            "f": [true, false, null, 1.5],
+            "l": [1, 2],
+        });
+        let mut buf = String::new();
+        let mut index = 3;
+        options.write_csv(value, None, &mut index, &mut buf)?;
+        assert_eq!(index, 5);
+        let expected = r#""a,b","x, ""y""",3
+"c.d
+e","line 1
+line 2",3
+f.0,1,3
+f.1,0,3
+f.2,,3
+f.3,1.5,3
+l,1,3
+"a,b","x, ""y""",4
+"c.d
+e","line 1
+line 2",4
+f.0,1,4
+f.1,0,4
+f.2,,4
+f.3,1.5,4
+l,2,4
+"#;
+        assert_eq!(buf, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn stringify_deep() -> Result<()> {
+        let value = json!({ "a": { "b": { "c": [1, { "d": null }] }, "e": [] }, "f": "x", "g": 2 });
+        for (depth, flat) in [
+            (
+                1,
+                r#"{"a":"{\"b\":{\"c\":[1,{\"d\":null}]},\"e\":[]}","f":"x","g":2}"#,
+            ),
+            (
+                2,
+                r#"{"a.b":"{\"c\":[1,{\"d\":null}]}","a.e":"[]","f":"x","g":2}"#,
+            ),
+        ] {
+            let mut options = Flatten {
+                max_depth: Some(depth),
+                stringify_deep: true,
+                parse_leaves: true,
+                ..options()
+            };
+            let out = options
+                .process_one(value.clone(), serde_json::value::Serializer)?
+                .unwrap();
+            assert_eq!(serde_json::to_string(&out)?, flat);
+            assert!(out
+                .as_object()
+                .unwrap()
+                .values()
+                .all(|v| !v.is_object() && !v.is_array()));
+
+            let unflat = serde_json::to_value(options.unflatten(out)?)?;
+            assert_eq!(unflat, value);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn low_memory() -> Result<()> {
+        let value = load_json("tests/recursive.json")?;
+        let to_bytes = |options: &mut Flatten, value: Value| -> Result<Vec<u8>> {
+            let mut out = Vec::new();
+            options.process_one(value, &mut serde_json::Serializer::new(&mut out))?;
+            Ok(out)
+        };
+        let variants = [
+            options(),
+            brackets(),
+            Flatten {
+                escape: Some('\\'),
+                drop_empty: true,
+                max_depth: Some(2),
+                ..options()
+            },
+            Flatten {
+                pointer: true,
+                exclude: vec![Regex::new("^/d")?],
+                ..options()
+            },
+        ];
+        for options in variants {
+            let buffered = to_bytes(&mut options.clone(), value.clone())?;
+            let mut streaming = Flatten {
+                low_memory: true,
+                ..options
+            };
+            assert_eq!(to_bytes(&mut streaming, value.clone())?, buffered);
+        }
+
+        // Keys which occur more than once are only looked for with --strict
+        let mut streaming = Flatten {
+            low_memory: true,
+            sep: String::new(),
+            ..options()
+        };
+        let value = json!({ "ab": 1, "a": { "b": 2 } });
+        assert_eq!(
+            to_bytes(&mut streaming, value.clone())?,
+            br#"{"ab":1,"ab":2}"#
+        );
+        streaming.strict = true;
+        let err = to_bytes(&mut streaming, value).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"key "ab" comes from both /ab and /a/b after flattening (use --escape to avoid this)"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn strict_separator() -> Result<()> {
+        let mut strict = Flatten {
+            strict: true,
+            ..options()
+        };
+        let flatten = |options: &mut Flatten, value: Value| {
+            options.process_one(value, serde_json::value::Serializer)
+        };
+        let value = json!({ "a": { "b.c": 1 } });
+
+        let err = flatten(&mut strict, value.clone()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"key "b.c" contains the separator ".", use another separator with -d or escape it with --escape"#
+        );
+        assert!(flatten(&mut options(), value.clone())?.is_some());
+        strict.escape = Some('\\');
+        assert!(flatten(&mut strict, value.clone())?.is_some());
+        strict.escape = None;
+        strict.max_depth = Some(1);
+        assert!(flatten(&mut strict, value)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn max_array_len() -> Result<()> {
+        let value = json!({ "a": [1, 2, 3], "b": { "c": [[1, 2, 3], [4]] }, "d": [5, 6], "e": [] });
+        let flatten = |options: Flatten| -> Result<String> {
+            let mut flat = IndexMap::new();
+            options.flatten(&mut flat, &mut String::new(), value.clone(), 0)?;
+            Ok(serde_json::to_string(&flat)?)
+        };
+
+        let truncate = Flatten {
+            max_array_len: Some(2),
+            ..options()
+        };
+        assert_eq!(
+            flatten(truncate.clone())?,
+            r#"{"a.0":1,"a.1":2,"a.__truncated":3,"b.c.0.0":1,"b.c.0.1":2,"b.c.0.__truncated":3,"b.c.1.0":4,"d.0":5,"d.1":6,"e":[]}"#
+        );
+        assert_eq!(
+            flatten(Flatten {
+                brackets: true,
+                ..truncate.clone()
+            })?,
+            r#"{"a[0]":1,"a[1]":2,"a.__truncated":3,"b.c[0][0]":1,"b.c[0][1]":2,"b.c[0].__truncated":3,"b.c[1][0]":4,"d[0]":5,"d[1]":6,"e":[]}"#
+        );
+        assert_eq!(
+            flatten(Flatten {
+                max_array_len_drop: true,
+                ..truncate
+            })?,
+            r#"{"b.c.1.0":4,"d.0":5,"d.1":6,"e":[]}"#
+        );
+        assert_eq!(
+            flatten(Flatten {
+                max_array_len: Some(3),
+                ..options()
+            })?,
+            flatten(options())?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn key_case() -> Result<()> {
+        let value = json!({ "userId": 1, "HTTPHeaders": [{ "content-type": "x" }], "_id": 2 });
+        for (case, expected) in [
+            (
+                KeyCase::Snake,
+                json!({ "user_id": 1, "http_headers.0.content_type": "x", "_id": 2 }),
+            ),
+            (
+                KeyCase::Camel,
+                json!({ "userId": 1, "httpHeaders.0.contentType": "x", "_id": 2 }),
+            ),
+            (
+                KeyCase::Kebab,
+                json!({ "user-id": 1, "http-headers.0.content-type": "x", "_id": 2 }),
+            ),
+            (
+                KeyCase::Lower,
+                json!({ "userid": 1, "httpheaders.0.content-type": "x", "_id": 2 }),
+            ),
+            (
+                KeyCase::Upper,
+                json!({ "USERID": 1, "HTTPHEADERS.0.CONTENT-TYPE": "x", "_ID": 2 }),
+            ),
+        ] {
+            let mut flatten = Flatten {
+                key_case: Some(case),
+                ..options()
+            };
+            assert_eq!(
+                flatten.process_one(value.clone(), serde_json::value::Serializer)?,
+                Some(expected),
+                "{:?}",
+                case
+            );
+        }
+
+        let mut flatten = Flatten {
+            key_case: Some(KeyCase::Snake),
+            ..brackets()
+        };
+        assert_eq!(
+            flatten.process_one(
+                json!({ "aB": [{ "cD": 1 }] }),
+                serde_json::value::Serializer
+            )?,
+            Some(json!({ "a_b[0].c_d": 1 }))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn key_case_collisions() -> Result<()> {
+        let value = json!({ "user": { "userId": 1, "user_id": 2 } });
+        let mut snake = Flatten {
+            key_case: Some(KeyCase::Snake),
+            ..options()
+        };
+        assert_eq!(
+            snake.process_one(value.clone(), serde_json::value::Serializer)?,
+            Some(json!({ "user.user_id": 2 }))
+        );
+
+        // Reported even when keys are escaped
+        let mut strict = Flatten {
+            key_case: Some(KeyCase::Snake),
+            escape: Some('\\'),
+            strict: true,
+            ..options()
+        };
+        let err = strict
+            .process_one(value, serde_json::value::Serializer)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"key "user.user_id" comes from both /user/userId and /user/user_id after flattening"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn key_collisions() -> Result<()> {
+        let flatten = |options: &mut Flatten, value: Value| {
+            options.process_one(value, serde_json::value::Serializer)
+        };
+        let value = json!({ "a": { "b": 1 }, "x": 0, "a.b": 2 });
+        assert_eq!(
+            flatten(&mut options(), value.clone())?,
+            Some(json!({ "a.b": 2, "x": 0 }))
+        );
+        let mut escaped = Flatten {
+            escape: Some('\\'),
+            strict: true,
+            ..options()
+        };
+        assert_eq!(
+            flatten(&mut escaped, value)?,
+            Some(json!({ "a.b": 1, "x": 0, "a\\.b": 2 }))
+        );
+
+        let mut strict = Flatten {
+            sep: String::new(),
+            strict: true,
+            ..options()
+        };
+        let err = flatten(&mut strict, json!({ "ab": 1, "a": { "b": 2 } })).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"key "ab" comes from both /ab and /a/b after flattening (use --escape to avoid this)"#
+        );
+
+        let mut strict = Flatten {
+            strict: true,
+            ..brackets()
+        };
+        let value = json!({ "x/y": [1], "x/y[0]": 2 });
+        let err = flatten(&mut strict, value).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"key "x/y[0]" comes from both /x~1y/0 and /x~1y[0] after flattening (use --escape to avoid this)"#
+        );
+
+        // Every path is reported, and nulls dropped with --drop-nulls don't count
+        let mut all_paths = Flatten {
+            sep: String::new(),
+            strict: true,
+            drop_nulls: true,
+            ..options()
+        };
+        let value = json!({ "abc": 1, "a": { "bc": 2, "b": { "c": null } }, "ab": { "c": 3 } });
+        let err = flatten(&mut all_paths, value).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"key "abc" comes from /abc, /a/bc and /ab/c after flattening (use --escape to avoid this)"#
+        );
+
+        // Keys are only compared within a record
+        let value = json!({ "a": { "b": 1 } });
+        assert!(flatten(&mut strict, value.clone())?.is_some());
+        assert!(flatten(&mut strict, value)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn strict_conflicts() {
+        let strict = Flatten {
+            strict: true,
+            ..options()
+        };
+        let conflict = |options: &Flatten, value: Value| {
+            options
+                .unflatten(value)
+                .map(|_| ())
+                .unwrap_err()
+                .to_string()
+        };
+
+        let err = conflict(&strict, json!({ "a": 2, "x": 1, "a.b": [1] }));
+        assert_eq!(
+            err,
+            r#"keys "a" and "a.b" conflict: "a" has a value, so no keys can be nested inside it"#
+        );
+        let err = conflict(&strict, json!({ "a.b.c": [1], "a": 2 }));
+        assert_eq!(
+            err,
+            r#"keys "a.b.c" and "a" conflict: "a" has a value, so no keys can be nested inside it"#
+        );
+        let err = conflict(&strict, json!({ "a.b": null, "a.b.0": 1 }));
+        assert_eq!(
+            err,
+            r#"keys "a.b" and "a.b.0" conflict: "a.b" has a value, so no keys can be nested inside it"#
+        );
+        let err = conflict(&strict, json!({ "a.b.0": 1, "x": {}, "a.b": null }));
+        assert_eq!(
+            err,
+            r#"keys "a.b.0" and "a.b" conflict: "a.b" has a value, so no keys can be nested inside it"#
+        );
+        // Without --strict, the branch wins whatever order the keys are in
+        for value in [json!({ "a": 5, "a.b": 1 }), json!({ "a.b": 1, "a": 5 })] {
+            assert_eq!(unflatten(value), json!({ "a": { "b": 1 } }));
+        }
+
+        let brackets = Flatten {
+            brackets: true,
+            ..strict
+        };
+        let err = conflict(&brackets, json!({ "a.0": 1, "a[0]": 2 }));
+        assert_eq!(
+            err,
+            r#"keys "a.0" and "a[0]" conflict: they have different values for the same path"#
+        );
+        assert!(brackets.unflatten(json!({ "a.0": 1, "a[0]": 1 })).is_ok());
+        assert!(brackets
+            .unflatten(json!({ "a[0]": 1, "a[1].b": 1, "a[1].c": 1, "b": {} }))
+            .is_ok());
+    }
+
+    #[test]
+    fn merge_records() -> Result<()> {
+        let records = [
+            json!({ "a.b": 1, "list.0": "x" }),
+            json!({ "a.c": 2, "list.1": "y" }),
+            json!({ "d": null, "a.b": 1 }),
+        ];
+        let merge = |options: Flatten| -> Result<String> {
+            let mut unflatten = Unflatten::new(Flatten {
+                merge: true,
+                ..options
+            });
+            for r in records.clone() {
+                assert!(unflatten
+                    .process_one(r, serde_json::value::Serializer)?
+                    .is_none());
+            }
+            let merged = unflatten.finish(serde_json::value::Serializer)?.unwrap();
+            Ok(serde_json::to_string(&merged)?)
+        };
+
+        assert_eq!(
+            merge(options())?,
+            r#"{"a":{"b":1,"c":2},"list":{"0":"x","1":"y"},"d":null}"#
+        );
+        let arrays = Flatten {
+            arrays: true,
+            ..options()
+        };
+        assert_eq!(
+            merge(arrays)?,
+            r#"{"a":{"b":1,"c":2},"list":["x","y"],"d":null}"#
+        );
+
+        let mut strict = Unflatten::new(Flatten {
+            merge: true,
+            strict: true,
+            ..options()
+        });
+        strict.merge(json!({ "a.b": 1 }))?;
+        let err = strict.merge(json!({ "a": 2 })).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"keys "a.b" and "a" conflict: "a" has a value, so no keys can be nested inside it"#
+        );
+        Ok(())
+    }
+
     #[test]
     #[should_panic]
     fn bad_top_level_object() {
         unflatten(Value::Null);
     }
 
+    #[test]
+    fn lenient_non_objects() -> Result<()> {
+        let stream = [
+            json!({ "a.b": 1 }),
+            json!("ping"),
+            json!([{ "c.d": 2 }]),
+            json!(null),
+            json!({ "a.c": 3 }),
+        ];
+        let run = |merge: bool| -> Result<Vec<String>> {
+            let mut unflatten = Unflatten::new(Flatten {
+                lenient: true,
+                merge,
+                ..options()
+            });
+            let mut out = Vec::new();
+            for value in stream.clone() {
+                if let Some(v) = unflatten.process_one(value, serde_json::value::Serializer)? {
+                    out.push(serde_json::to_string(&v)?);
+                }
+            }
+            if let Some(v) = unflatten.finish(serde_json::value::Serializer)? {
+                out.push(serde_json::to_string(&v)?);
+            }
+            Ok(out)
+        };
+
+        assert_eq!(
+            run(false)?,
+            [
+                r#"{"a":{"b":1}}"#,
+                r#""ping""#,
+                r#"[{"c.d":2}]"#,
+                "null",
+                r#"{"a":{"c":3}}"#
+            ]
+        );
+        assert_eq!(
+            run(true)?,
+            [
+                r#""ping""#,
+                r#"[{"c.d":2}]"#,
+                "null",
+                r#"{"a":{"b":1,"c":3}}"#
+            ]
+        );
+        Ok(())
+    }
+
     #[test]
     fn simple() {
         let original = json! ({
@@ -227,4 +2500,769 @@ mod tests {
         });
         assert_eq!(flatten(original), flat);
     }
+
+    #[test]
+    #[cfg(feature = "precise-numbers")]
+    fn precise_numbers() {
+        let int = "1234567890123456789012345";
+        let dec = "3.14159265358979323846264338327";
+        for n in [int, dec, "12345678901234567890", "1.50"] {
+            let flat_text = format!(r#"{{"a.b":{}}}"#, n);
+            let nested_text = format!(r#"{{"a":{{"b":{}}}}}"#, n);
+            let flat: Value = serde_json::from_str(&flat_text).unwrap();
+            let u = options().unflatten(flat).unwrap();
+            assert_eq!(serde_json::to_string(&u).unwrap(), nested_text);
+
+            let nested: Value = serde_json::from_str(&nested_text).unwrap();
+            assert_eq!(serde_json::to_string(&flatten(nested)).unwrap(), flat_text);
+        }
+    }
+
+    fn brackets() -> Flatten {
+        Flatten {
+            brackets: true,
+            ..options()
+        }
+    }
+
+    fn round_trip(options: &Flatten, value: Value) -> (Value, Value) {
+        let mut m = IndexMap::new();
+        let mut key = options.prefix.clone().unwrap_or_default();
+        options.flatten(&mut m, &mut key, value, 0).unwrap();
+        let flat = serde_json::to_value(&m).unwrap();
+        let u = options.unflatten(flat.clone()).unwrap();
+        (flat, serde_json::to_value(&u).unwrap())
+    }
+
+    #[test]
+    fn parse_bracket_keys() {
+        use Segment::*;
+        fn parse(key: &str) -> Vec<Segment<'_>> {
+            let mut segments = Vec::new();
+            key.split('.')
+                .for_each(|part| parse_brackets(part, None, false, &mut segments));
+            segments
+        }
+        assert_eq!(
+            parse("a[0].b"),
+            [Key("a".into()), Index(0), Key("b".into())]
+        );
+        assert_eq!(parse("a[0][12]"), [Key("a".into()), Index(0), Index(12)]);
+        assert_eq!(parse("[1].a"), [Index(1), Key("a".into())]);
+        assert_eq!(parse("a[x][1]"), [Key("a[x]".into()), Index(1)]);
+        assert_eq!(parse("a[01]"), [Key("a[01]".into())]);
+        assert_eq!(parse("a[]"), [Key("a[]".into())]);
+        assert_eq!(parse("[a"), [Key("[a".into())]);
+        assert_eq!(parse("a]"), [Key("a]".into())]);
+        assert_eq!(parse(""), [Key("".into())]);
+    }
+
+    #[test]
+    fn brackets_round_trip() -> Result<()> {
+        // "e" has a key containing the separator, which doesn't survive a round trip without
+        // --escape
+        let mut original = load_json("tests/recursive.json")?;
+        original.as_object_mut().unwrap().remove("e");
+        let (flat, unflat) = round_trip(&brackets(), original.clone());
+        assert_eq!(flat["d.c[2]"], json!(3));
+        assert_eq!(unflat, original);
+
+        let original = json!({
+            "a": [[1, 2], [], [{ "b": [3] }]],
+            "0": { "1": "x" },
+            "x[": 1,
+            "y[z]": [true],
+            "n": null,
+        });
+        let (flat, unflat) = round_trip(&brackets(), original.clone());
+        assert_eq!(
+            flat,
+            json!({
+                "a[0][0]": 1,
+                "a[0][1]": 2,
+                "a[1]": [],
+                "a[2][0].b[0]": 3,
+                "0.1": "x",
+                "x[": 1,
+                "y[z][0]": true,
+                "n": null,
+            })
+        );
+        assert_eq!(unflat, original);
+
+        let (flat, unflat) = round_trip(&brackets(), json!([{ "a": 1 }, [2]]));
+        assert_eq!(flat, json!({ "[0].a": 1, "[1][0]": 2 }));
+        assert_eq!(unflat, json!([{ "a": 1 }, [2]]));
+
+        let nested: Vec<_> = (0..12).map(|i| json!([[i], { "b": [i, [i]] }])).collect();
+        let original = json!({ "a": nested });
+        let (flat, unflat) = round_trip(&brackets(), original.clone());
+        assert_eq!(flat["a[11][1].b[1][0]"], json!(11));
+        assert_eq!(flat.as_object().unwrap().len(), 36);
+        assert_eq!(unflat, original);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_containers() {
+        let original = json!({
+            "a": {},
+            "b": [],
+            "c": { "d": {}, "e": [[], { "f": [] }] },
+            "g": 1,
+        });
+        let (flat, unflat) = round_trip(&options(), original.clone());
+        assert_eq!(
+            flat,
+            json!({
+                "a": {},
+                "b": [],
+                "c.d": {},
+                "c.e.0": [],
+                "c.e.1.f": [],
+                "g": 1,
+            })
+        );
+        assert_eq!(unflat["a"], original["a"]);
+        assert_eq!(unflat["b"], original["b"]);
+        assert_eq!(unflat["c"]["d"], original["c"]["d"]);
+        assert_eq!(unflat["c"]["e"], json!({ "0": [], "1": { "f": [] } }));
+
+        let (_, unflat) = round_trip(&brackets(), original.clone());
+        assert_eq!(unflat, original);
+
+        let (flat, _) = round_trip(&options(), json!({}));
+        assert_eq!(flat, json!({}));
+
+        let drop_empty = Flatten {
+            drop_empty: true,
+            ..options()
+        };
+        let (flat, _) = round_trip(&drop_empty, original);
+        assert_eq!(flat, json!({ "g": 1 }));
+    }
+
+    #[test]
+    fn escaped_keys() -> Result<()> {
+        let escape = Flatten {
+            escape: Some('\\'),
+            ..options()
+        };
+        let original = json!({
+            "a.b": 1,
+            "a": { "b": 2, "c\\": { ".": 3 } },
+            "d\\.e": [4],
+            "\\": 5,
+        });
+        let (flat, unflat) = round_trip(&escape, original.clone());
+        assert_eq!(
+            flat,
+            json!({
+                r"a\.b": 1,
+                "a.b": 2,
+                r"a.c\\.\.": 3,
+                r"d\\\.e.0": 4,
+                r"\\": 5,
+            })
+        );
+        assert_eq!(unflat["a.b"], original["a.b"]);
+        assert_eq!(unflat["a"], original["a"]);
+        assert_eq!(unflat[r"d\.e"], json!({ "0": 4 }));
+        assert_eq!(unflat[r"\"], original[r"\"]);
+
+        let escape_brackets = Flatten {
+            brackets: true,
+            ..escape.clone()
+        };
+        let (_, unflat) = round_trip(&escape_brackets, load_json("tests/recursive.json")?);
+        assert_eq!(unflat, load_json("tests/recursive.json")?);
+
+        let original = json!({ "a[0]": { "b~": [1, { "c::d": 2 }] } });
+        let tilde = Flatten {
+            sep: "::".to_string(),
+            escape: Some('~'),
+            ..escape_brackets
+        };
+        let (flat, unflat) = round_trip(&tilde, original.clone());
+        assert_eq!(
+            flat,
+            json!({ "a~[0]::b~~[0]": 1, "a~[0]::b~~[1]::c~::d": 2 })
+        );
+        assert_eq!(unflat, original);
+        Ok(())
+    }
+
+    #[test]
+    fn rebuild_arrays() -> Result<()> {
+        let arrays = Flatten {
+            escape: Some('\\'),
+            arrays: true,
+            ..options()
+        };
+        let original = load_json("tests/recursive.json")?;
+        let (_, unflat) = round_trip(&arrays, original.clone());
+        assert_eq!(unflat, original);
+
+        let original = json!({
+            "a": [[1, 2], [], [{ "b": [3] }, {}]],
+            "c": { "0": "x", "01": "y" },
+            "d": [{ "0": [null] }],
+        });
+        let (_, unflat) = round_trip(&arrays, original.clone());
+        assert_eq!(unflat["a"], original["a"]);
+        assert_eq!(unflat["c"], original["c"]);
+        assert_eq!(unflat["d"], json!([[[null]]]));
+
+        let gaps = json!({ "a.0": 1, "a.2": 3, "b.1.c": true });
+        assert!(arrays.unflatten(gaps.clone()).is_err());
+        let fill_gaps = Flatten {
+            fill_gaps: true,
+            ..arrays
+        };
+        let u = serde_json::to_value(fill_gaps.unflatten(gaps)?)?;
+        assert_eq!(u, json!({ "a": [1, null, 3], "b": [null, { "c": true }] }));
+        Ok(())
+    }
+
+    #[test]
+    fn sparse_policies() -> Result<()> {
+        let gaps = json!({ "a.0": 1, "a.2": 3, "b.01": 1, "b.0": 2 });
+        let unflatten = |sparse: Sparse| {
+            let options = Flatten {
+                arrays: true,
+                sparse: Some(sparse),
+                ..options()
+            };
+            options
+                .unflatten(gaps.clone())
+                .and_then(|u| Ok(serde_json::to_string(&u)?))
+        };
+
+        assert_eq!(
+            unflatten(Sparse::Null)?,
+            r#"{"a":[1,null,3],"b":{"01":1,"0":2}}"#
+        );
+        assert_eq!(
+            unflatten(Sparse::Object)?,
+            r#"{"a":{"0":1,"2":3},"b":{"01":1,"0":2}}"#
+        );
+        assert_eq!(
+            unflatten(Sparse::Error).unwrap_err().to_string(),
+            "array is missing index 1 (2 of 3 elements present)"
+        );
+        assert!("nulls".parse::<Sparse>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn toml_dotted_keys() -> Result<()> {
+        let options = Flatten {
+            escape: Some('\\'),
+            ..options()
+        };
+        let original = json!({
+            "a.b": 1,
+            "a": { "c": 2, "d.e": [{ "f.g": 3 }] },
+            "a.d.h": null,
+        });
+        let split = options.split_dotted_keys(original)?;
+        assert_eq!(
+            split,
+            json!({ "a": { "b": 1, "c": 2, "d": { "e": [{ "f": { "g": 3 } }], "h": null } } })
+        );
+        let mut flat = IndexMap::new();
+        options.flatten(&mut flat, &mut String::new(), split, 0)?;
+        assert_eq!(
+            serde_json::to_value(&flat)?,
+            json!({ "a.b": 1, "a.c": 2, "a.d.e.0.f.g": 3, "a.d.h": null })
+        );
+
+        assert!(options
+            .split_dotted_keys(json!({ "a": 1, "a.b": 2 }))
+            .is_err());
+        assert!(options
+            .split_dotted_keys(json!({ "a.b": { "c": 1 }, "a": { "b": { "c": 2 } } }))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn max_depth() -> Result<()> {
+        let original = load_json("tests/recursive.json")?;
+        let run = |max_depth| {
+            let mut options = Flatten {
+                max_depth: Some(max_depth),
+                ..options()
+            };
+            options
+                .process_one(original.clone(), serde_json::value::Serializer)
+                .map(Option::unwrap)
+        };
+
+        assert_eq!(run(0)?, original);
+        assert_eq!(run(1)?, original);
+        assert_eq!(
+            run(2)?,
+            json!({
+                "a": 12,
+                "b.0": "hello",
+                "c.0": 1,
+                "c.1": 2,
+                "c.2": 3,
+                "d.c": [1, 2, 3],
+                "d.foo": "bar",
+                "e.e.json": false,
+            })
+        );
+        assert_eq!(run(3)?, load_json("tests/recursive-flat.json")?);
+
+        let mut options = Flatten {
+            max_depth: Some(2),
+            ..options()
+        };
+        let x = options.process_one(
+            json!({ "a": { "b": { "c": 1 } } }),
+            serde_json::value::Serializer,
+        )?;
+        assert_eq!(x, Some(json!({ "a.b": { "c": 1 } })));
+        Ok(())
+    }
+
+    #[test]
+    fn prefix() -> Result<()> {
+        let prefixed = Flatten {
+            prefix: Some("doc".to_string()),
+            strip_prefix: Some("doc".to_string()),
+            ..options()
+        };
+        let original = json!({ "a": { "b": 1 }, "c": [2] });
+        let flat = prefixed
+            .clone()
+            .process_one(original.clone(), serde_json::value::Serializer)?;
+        assert_eq!(flat, Some(json!({ "doc.a.b": 1, "doc.c.0": 2 })));
+        let unflat = serde_json::to_value(prefixed.unflatten(flat.unwrap())?)?;
+        assert_eq!(unflat, json!({ "a": { "b": 1 }, "c": { "0": 2 } }));
+
+        let (flat, unflat) = round_trip(&prefixed, json!({}));
+        assert_eq!((flat, unflat), (json!({}), json!(null)));
+        let with_arrays = Flatten {
+            arrays: true,
+            ..prefixed.clone()
+        };
+        let (_, unflat) = round_trip(&with_arrays, json!({ "a": [{ "b": [1] }] }));
+        assert_eq!(unflat, json!({ "a": [{ "b": [1] }] }));
+
+        let other_keys = json!({ "doc.a": 1, "x.y": 2, "docs": 3 });
+        let err = prefixed.unflatten(other_keys.clone()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"key "x.y" doesn't start with the prefix "doc""#
+        );
+        let lenient = Flatten {
+            lenient: true,
+            ..prefixed
+        };
+        let unflat = serde_json::to_value(lenient.unflatten(other_keys)?)?;
+        assert_eq!(unflat, json!({ "a": 1, "x": { "y": 2 }, "docs": 3 }));
+
+        let mut brackets = Flatten {
+            brackets: true,
+            sep: "/".to_string(),
+            prefix: Some("doc".to_string()),
+            ..options()
+        };
+        let flat = brackets.process_one(json!([{ "a": 1 }]), serde_json::value::Serializer)?;
+        assert_eq!(flat, Some(json!({ "doc[0]/a": 1 })));
+        let brackets = Flatten {
+            strip_prefix: Some("doc".to_string()),
+            ..brackets
+        };
+        let (_, unflat) = round_trip(&brackets, json!([{ "a": 1 }]));
+        assert_eq!(unflat, json!([{ "a": 1 }]));
+        Ok(())
+    }
+
+    #[test]
+    fn include_exclude() -> Result<()> {
+        let original = load_json("tests/recursive.json")?;
+        let filtered = |include: &[&str], exclude: &[&str]| {
+            let mut options = Flatten {
+                include: include.iter().map(|r| Regex::new(r).unwrap()).collect(),
+                exclude: exclude.iter().map(|r| Regex::new(r).unwrap()).collect(),
+                ..options()
+            };
+            options
+                .process_one(original.clone(), serde_json::value::Serializer)
+                .map(Option::unwrap)
+        };
+
+        assert_eq!(filtered(&[], &[])?, load_json("tests/recursive-flat.json")?);
+        assert_eq!(
+            filtered(&[r"^d\.", "^a$"], &[])?,
+            json!({ "a": 12, "d.c.0": 1, "d.c.1": 2, "d.c.2": 3, "d.foo": "bar" })
+        );
+        assert_eq!(
+            filtered(&[r"^d\."], &[r"\.\d+$"])?,
+            json!({ "d.foo": "bar" })
+        );
+        assert_eq!(
+            filtered(&[], &[r"^[cde]\."])?,
+            json!({ "a": 12, "b.0": "hello" })
+        );
+
+        let mut options = Flatten {
+            include: vec![Regex::new("x")?],
+            ..options()
+        };
+        let x = options.process_one(json!(1), serde_json::value::Serializer)?;
+        assert_eq!(x, Some(json!(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn keep_arrays() -> Result<()> {
+        let options = Flatten {
+            keep_arrays: true,
+            ..options()
+        };
+        let original = json!({
+            "a": { "tags": ["x", "y"], "b": 1 },
+            "c": [{ "d": [1, { "e": 2 }] }],
+            "f": [],
+        });
+        let (flat, unflat) = round_trip(&options, original.clone());
+        assert_eq!(
+            flat,
+            json!({
+                "a.tags": ["x", "y"],
+                "a.b": 1,
+                "c": [{ "d": [1, { "e": 2 }] }],
+                "f": [],
+            })
+        );
+        assert_eq!(unflat, original);
+        Ok(())
+    }
+
+    #[test]
+    fn pointer() -> Result<()> {
+        let pointer = Flatten {
+            pointer: true,
+            ..options()
+        };
+        let original = json!({
+            "a/b": 1,
+            "a": { "b": 2, "c~": { "d.e": [3, { "~1": 4 }] } },
+            "": { "": 5 },
+        });
+        let (flat, unflat) = round_trip(&pointer, original.clone());
+        assert_eq!(
+            flat,
+            json!({
+                "/a~1b": 1,
+                "/a/b": 2,
+                "/a/c~0/d.e/0": 3,
+                "/a/c~0/d.e/1/~01": 4,
+                "//": 5,
+            })
+        );
+        assert_eq!(unflat["a/b"], original["a/b"]);
+        assert_eq!(unflat[""], original[""]);
+        assert_eq!(unflat["a"]["c~"]["d.e"]["1"], json!({ "~1": 4 }));
+
+        let arrays = Flatten {
+            arrays: true,
+            ..pointer.clone()
+        };
+        let (_, unflat) = round_trip(&arrays, original.clone());
+        assert_eq!(unflat, original);
+        let (flat, unflat) = round_trip(&arrays, json!([[1], { "0": 2 }]));
+        assert_eq!(flat, json!({ "/0/0": 1, "/1/0": 2 }));
+        assert_eq!(unflat, json!([[1], [2]]));
+
+        let prefixed = Flatten {
+            prefix: Some("/doc".to_string()),
+            strip_prefix: Some("/doc".to_string()),
+            ..arrays
+        };
+        let (flat, unflat) = round_trip(&prefixed, json!({ "a": [1] }));
+        assert_eq!(flat, json!({ "/doc/a/0": 1 }));
+        assert_eq!(unflat, json!({ "a": [1] }));
+
+        for key in ["a/b", "/a~2", "/a~"] {
+            let err = pointer.unflatten(json!({ key: 1 })).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("key {:?} isn't a JSON Pointer", key)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn kv_format() -> Result<()> {
+        let mut options = Flatten {
+            escape: Some('\\'),
+            arrays: true,
+            ..options()
+        };
+        let original = json!({
+            "a": { "b": "x=y", "c": "two\nlines\\" },
+            "d": [1, true, null, "", "2", []],
+            "e=f": {},
+        });
+        let mut kv = String::new();
+        options.write_kv(original.clone(), None, &mut kv)?;
+        assert_eq!(
+            kv,
+            r#"a.b=x\=y
+a.c=two\nlines\\
+d.0=1
+d.1=true
+d.2=
+d.3=
+d.4=2
+d.5=[]
+e\=f={}
+"#
+        );
+
+        let read = |options: &Flatten, kv: &str| -> Result<Vec<Value>> {
+            let mut records = Vec::new();
+            options.read_kv(kv.as_bytes(), |record| {
+                records.push(serde_json::to_value(
+                    options.unflatten(Value::Object(record))?,
+                )?);
+                Ok(())
+            })?;
+            Ok(records)
+        };
+        let records = read(&options, &format!("{}\n\n{}", kv, kv))?;
+        assert_eq!(records.len(), 2);
+        let unflat = &records[0];
+        assert_eq!(unflat["a"], original["a"]);
+        assert_eq!(unflat["d"], json!([1, true, null, null, 2, []]));
+        assert_eq!(unflat["e=f"], original["e=f"]);
+
+        let strings = Flatten {
+            string_values: true,
+            ..options.clone()
+        };
+        let unflat = &read(&strings, &kv)?[0];
+        assert_eq!(unflat["d"], json!(["1", "true", "", "", "2", "[]"]));
+
+        let err = read(&options, "a=1\nb\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: expected `key=value`");
+        assert!(options
+            .write_kv(json!(1), None, &mut String::new())
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn explode() -> Result<()> {
+        let run = |explode: &[&str], drop_missing: bool, value: Value| -> Result<Vec<Value>> {
+            let mut options = Flatten {
+                explode: explode.iter().map(|p| p.to_string()).collect(),
+                explode_drop_missing: drop_missing,
+                ..options()
+            };
+            let mut records = Vec::new();
+            for record in options.split_record(value)? {
+                records.extend(options.process_one(record, serde_json::value::Serializer)?);
+            }
+            Ok(records)
+        };
+
+        let record = json!({ "id": 1, "events": [{ "t": 1 }, { "t": 2 }] });
+        assert_eq!(
+            run(&["events"], false, record.clone())?,
+            [
+                json!({ "id": 1, "events.t": 1 }),
+                json!({ "id": 1, "events.t": 2 }),
+            ]
+        );
+
+        let record = json!({ "a": { "b": [1, 2], "c": 0 }, "d": ["x", "y"] });
+        assert_eq!(
+            run(&["a.b", "d"], false, record)?,
+            [
+                json!({ "a.b": 1, "a.c": 0, "d": "x" }),
+                json!({ "a.b": 1, "a.c": 0, "d": "y" }),
+                json!({ "a.b": 2, "a.c": 0, "d": "x" }),
+                json!({ "a.b": 2, "a.c": 0, "d": "y" }),
+            ]
+        );
+
+        let record = json!({ "e": [{ "tags": ["x", "y"] }, { "tags": [] }] });
+        assert_eq!(
+            run(&["e", "e.tags"], false, record.clone())?,
+            [
+                json!({ "e.tags": "x" }),
+                json!({ "e.tags": "y" }),
+                json!({ "e": {} }),
+            ]
+        );
+        assert_eq!(
+            run(&["e", "e.tags"], true, record)?,
+            [json!({ "e.tags": "x" }), json!({ "e.tags": "y" })]
+        );
+
+        let record = json!({ "id": 1, "events": [], "x": 2 });
+        assert_eq!(
+            run(&["events", "missing"], false, record.clone())?,
+            [json!({ "id": 1, "x": 2 })]
+        );
+        assert_eq!(run(&["events"], true, record.clone())?, Vec::<Value>::new());
+        assert_eq!(run(&["missing"], true, record)?, Vec::<Value>::new());
+        assert_eq!(run(&["a"], false, json!({ "a": 1 }))?, [json!({ "a": 1 })]);
+        Ok(())
+    }
+
+    #[test]
+    fn drop_nulls() -> Result<()> {
+        let original = json!({
+            "a": null,
+            "b": { "c": 1, "d": null, "e": [null, 2] },
+            "f": null,
+            "g": [null],
+            "h": "x",
+        });
+        let run = |options: Flatten| {
+            let mut options = Flatten {
+                drop_nulls: true,
+                ..options
+            };
+            let flat = options.process_one(original.clone(), serde_json::value::Serializer);
+            flat.map(|flat| serde_json::to_string(&flat).unwrap())
+        };
+
+        assert_eq!(run(options())?, r#"{"b.c":1,"b.e.1":2,"h":"x"}"#);
+        let keep_arrays = Flatten {
+            keep_arrays: true,
+            exclude: vec![Regex::new("^h$")?],
+            ..options()
+        };
+        assert_eq!(run(keep_arrays)?, r#"{"b.c":1,"b.e":[null,2],"g":[null]}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn sorted_keys() -> Result<()> {
+        let original = json!({ "b": (0..11).collect::<Vec<_>>(), "a": { "10": 1, "9": 2 } });
+        let mut flat = IndexMap::new();
+        options().flatten(&mut flat, &mut String::new(), original, 0)?;
+        let keys = |order: &str| -> Result<Vec<String>> {
+            let output = OutputOptions {
+                sort_keys: Some(order.parse()?),
+                ..Default::default()
+            };
+            let mut buf = Vec::new();
+            output.serialize_to(&mut buf, &flat)?;
+            let sorted: IndexMap<String, Value> = serde_json::from_slice(&buf)?;
+            Ok(sorted.into_keys().collect())
+        };
+
+        assert_eq!(
+            keys("lexical")?,
+            [
+                "a.10", "a.9", "b.0", "b.1", "b.10", "b.2", "b.3", "b.4", "b.5", "b.6", "b.7",
+                "b.8", "b.9"
+            ]
+        );
+        assert_eq!(
+            keys("natural")?,
+            [
+                "a.9", "a.10", "b.0", "b.1", "b.2", "b.3", "b.4", "b.5", "b.6", "b.7", "b.8",
+                "b.9", "b.10"
+            ]
+        );
+
+        let mut kv = String::new();
+        let original = json!({ "x": { "10": 1, "9": 2, "b": 3, "a": 4 } });
+        options().write_kv(original.clone(), Some(SortKeys::Natural), &mut kv)?;
+        assert_eq!(kv, "x.9=2\nx.10=1\nx.a=4\nx.b=3\n");
+        kv.clear();
+        options().write_kv(original, Some(SortKeys::Lexical), &mut kv)?;
+        assert_eq!(kv, "x.10=1\nx.9=2\nx.a=4\nx.b=3\n");
+        Ok(())
+    }
+
+    fn fidelity() -> Flatten {
+        Flatten {
+            fidelity: true,
+            brackets: true,
+            escape: Some('\\'),
+            ..options()
+        }
+    }
+
+    #[test]
+    fn fidelity_round_trip() {
+        let original = json!({
+            "": [1, { "": {} }],
+            "a": { "\"\"": [], "[0]": { "0": null } },
+            "b.c\\": [[], [[]], {}],
+        });
+        let (flat, unflat) = round_trip(&fidelity(), original.clone());
+        assert_eq!(
+            serde_json::to_string(&flat).unwrap(),
+            r#"{"\"\"[0]":1,"\"\"[1].\"\"":{},"a.\\\"\\\"":[],"a.\\[0].0":null,"b\\.c\\\\[0]":[],"b\\.c\\\\[1][0]":[],"b\\.c\\\\[2]":{}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&unflat).unwrap(),
+            serde_json::to_string(&original).unwrap()
+        );
+
+        for value in [
+            json!({}),
+            json!([]),
+            json!(1),
+            json!([[]]),
+            json!({ "": [] }),
+        ] {
+            let mut options = fidelity();
+            let flat = options
+                .process_one(value.clone(), serde_json::value::Serializer)
+                .unwrap()
+                .unwrap();
+            let unflat = serde_json::to_value(options.unflatten(flat).unwrap()).unwrap();
+            assert_eq!(unflat, value);
+        }
+    }
+
+    /// Keys made of characters with special meanings in flattened keys
+    const KEY: &str = r#"[ab0.\[\]"\\]{0,3}"#;
+
+    fn arb_value() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            "[a.\\[]{0,2}".prop_map(Value::from),
+        ];
+        leaf.prop_recursive(4, 32, 4, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..4).prop_map(Value::Array),
+                prop::collection::vec((KEY, inner), 0..4)
+                    .prop_map(|items| Value::Object(items.into_iter().collect())),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn fidelity_inverse(value in arb_value()) {
+            let mut options = fidelity();
+            let flat = options
+                .process_one(value.clone(), serde_json::value::Serializer)
+                .unwrap()
+                .unwrap();
+            let unflat = options.unflatten(flat).unwrap();
+            prop_assert_eq!(
+                serde_json::to_string(&unflat).unwrap(),
+                serde_json::to_string(&value).unwrap()
+            );
+        }
+    }
 }
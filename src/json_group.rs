@@ -0,0 +1,273 @@
+use std::io::{BufReader, Read};
+
+use indexmap::IndexMap;
+use json_tools::*;
+use posix_cli_utils::*;
+use serde_json::{Map, Value};
+
+/// Group records by the values of one or more keys, and output one object per group with the
+/// number of records in the group and optionally sums and averages of other keys.
+///
+/// Groups are written in the order they are first seen.  Each output object has the `--by` keys
+/// (with the group's values), `count`, then `sum(KEY)` and `avg(KEY)` for each `--sum` and `--avg`.
+#[derive(Debug, Clone, Args)]
+struct Group {
+    /// Group records by the value at KEY (eg. `a.b`).  Can be given multiple times, to group by
+    /// every combination of values.  Objects are the same regardless of key order, and numbers
+    /// regardless of how they're written (eg. `1` and `1.0`); the first value seen is output.  A
+    /// record without KEY is grouped as if KEY were `null`, unless --skip-missing is given.
+    #[clap(short = 'b', long, value_name = "KEY")]
+    by: Vec<String>,
+    /// Sum the numbers at KEY in each group
+    #[clap(long, value_name = "KEY")]
+    sum: Vec<String>,
+    /// Average the numbers at KEY in each group.  The average is `null` if no record in the group
+    /// has a number at KEY.
+    #[clap(long, value_name = "KEY")]
+    avg: Vec<String>,
+    /// Ignore records which are missing any of the --by keys, instead of grouping them under `null`
+    #[clap(long)]
+    skip_missing: bool,
+    /// Separator used to split keys into nested keys and array indices
+    #[clap(short = 'd', default_value = ".")]
+    sep: String,
+    /// The groups seen so far, by the canonical JSON of their --by values, see `write_canonical`
+    #[clap(skip)]
+    groups: IndexMap<String, Aggregate>,
+    /// The number of records read so far
+    #[clap(skip)]
+    records: usize,
+}
+
+#[derive(Debug, Clone, Parser)]
+struct ClArgs {
+    #[clap(flatten)]
+    input: InputOptions,
+    #[clap(flatten)]
+    options: Group,
+    #[clap(flatten)]
+    output: OutputOptions,
+}
+
+/// The running totals for one group
+#[derive(Debug, Clone)]
+struct Aggregate {
+    values: Vec<Value>,
+    count: usize,
+    sums: Vec<f64>,
+    /// The total and number of values for each `--avg` key
+    avgs: Vec<(f64, usize)>,
+}
+
+/// A float as a JSON number, written as an integer if it is one.
+fn number(x: f64) -> Value {
+    if x.fract() == 0.0 && x.abs() < (1u64 << 53) as f64 {
+        Value::from(x as i64)
+    } else {
+        Value::from(x)
+    }
+}
+
+impl Group {
+    fn lookup<'a>(&self, value: &'a Value, key: &str) -> Option<&'a Value> {
+        value.get_path(key.split(&*self.sep))
+    }
+
+    /// The number at `key`, or `None` if the record doesn't have `key` or it is `null`.
+    fn lookup_number(&self, value: &Value, key: &str) -> Result<Option<f64>> {
+        match self.lookup(value, key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(v) => Ok(Some(v.expect_f64().with_context(|| {
+                format!("record {}: cannot aggregate {:?}", self.records, key)
+            })?)),
+        }
+    }
+
+    fn add(&mut self, record: &Value) -> Result<()> {
+        let mut values = Vec::with_capacity(self.by.len());
+        for key in &self.by {
+            match self.lookup(record, key) {
+                Some(v) => values.push(v.clone()),
+                None if self.skip_missing => {
+                    self.records += 1;
+                    return Ok(());
+                }
+                None => values.push(Value::Null),
+            }
+        }
+        let sums = self
+            .sum
+            .iter()
+            .map(|k| self.lookup_number(record, k))
+            .collect::<Result<Vec<_>>>()?;
+        let avgs = self
+            .avg
+            .iter()
+            .map(|k| self.lookup_number(record, k))
+            .collect::<Result<Vec<_>>>()?;
+        self.records += 1;
+
+        let mut key = String::new();
+        for value in &values {
+            write_canonical(&mut key, value);
+            key.push(',');
+        }
+        let group = self.groups.entry(key).or_insert_with(|| Aggregate {
+            values,
+            count: 0,
+            sums: vec![0.0; sums.len()],
+            avgs: vec![(0.0, 0); avgs.len()],
+        });
+        group.count += 1;
+        for (total, x) in group.sums.iter_mut().zip(sums) {
+            *total += x.unwrap_or(0.0);
+        }
+        for ((total, n), x) in group.avgs.iter_mut().zip(avgs) {
+            if let Some(x) = x {
+                *total += x;
+                *n += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, input: impl Read) -> Result<()> {
+        for value in serde_json::Deserializer::from_reader(input).into_iter::<Value>() {
+            self.add(&value?)?;
+        }
+        Ok(())
+    }
+
+    /// One object per group, in the order the groups were first seen.
+    fn results(&self) -> impl Iterator<Item = Value> + '_ {
+        self.groups.values().map(move |group| {
+            let mut object = Map::new();
+            for (key, value) in self.by.iter().zip(&group.values) {
+                object.insert(key.clone(), value.clone());
+            }
+            object.insert("count".to_string(), group.count.into());
+            for (key, &total) in self.sum.iter().zip(&group.sums) {
+                object.insert(format!("sum({})", key), number(total));
+            }
+            for (key, &(total, n)) in self.avg.iter().zip(&group.avgs) {
+                let avg = if n == 0 {
+                    Value::Null
+                } else {
+                    number(total / n as f64)
+                };
+                object.insert(format!("avg({})", key), avg);
+            }
+            Value::Object(object)
+        })
+    }
+}
+
+fn main() -> Result<()> {
+    reset_sigpipe();
+    let mut args = ClArgs::parse();
+    let options = &mut args.options;
+    args.input.for_each(|input| match input {
+        Input::File(f) => options.read(BufReader::new(f)),
+        Input::Stdin(i) => options.read(i.lock()),
+    })?;
+    args.output.with_output(|w| {
+        for value in args.options.results() {
+            args.output.serialize_to(&mut *w, &value)?;
+            writeln!(w)?;
+        }
+        Ok(())
+    })?;
+    if args.output.count {
+        eprintln!("{}", args.options.records);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn group(by: &[&str], sum: &[&str], avg: &[&str]) -> Group {
+        let keys = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect();
+        Group {
+            by: keys(by),
+            sum: keys(sum),
+            avg: keys(avg),
+            skip_missing: false,
+            sep: ".".to_string(),
+            groups: IndexMap::new(),
+            records: 0,
+        }
+    }
+
+    fn run(mut options: Group, input: &str) -> Result<Vec<Value>> {
+        options.read(input.as_bytes())?;
+        Ok(options.results().collect())
+    }
+
+    const RECORDS: &str = r#"
+        {"user": {"country": "nz"}, "plan": "free", "bytes": 10, "ms": 1.5}
+        {"user": {"country": "au"}, "plan": "pro", "bytes": 20}
+        {"user": {"country": "nz"}, "plan": "pro", "bytes": 5.5, "ms": 2.5}
+        {"plan": "free", "bytes": null, "ms": 4}
+        {"user": {"country": "nz"}, "plan": "free", "ms": 3.5}
+    "#;
+
+    #[test]
+    fn count_sum_avg() -> Result<()> {
+        assert_eq!(
+            run(group(&["user.country"], &["bytes"], &["ms"]), RECORDS)?,
+            [
+                json!({ "user.country": "nz", "count": 3, "sum(bytes)": 15.5, "avg(ms)": 2.5 }),
+                json!({ "user.country": "au", "count": 1, "sum(bytes)": 20, "avg(ms)": null }),
+                json!({ "user.country": null, "count": 1, "sum(bytes)": 0, "avg(ms)": 4 }),
+            ]
+        );
+        assert_eq!(run(group(&[], &[], &[]), RECORDS)?, [json!({ "count": 5 })]);
+        Ok(())
+    }
+
+    #[test]
+    fn multiple_keys() -> Result<()> {
+        let options = Group {
+            skip_missing: true,
+            ..group(&["user.country", "plan"], &[], &[])
+        };
+        assert_eq!(
+            run(options, RECORDS)?,
+            [
+                json!({ "user.country": "nz", "plan": "free", "count": 2 }),
+                json!({ "user.country": "au", "plan": "pro", "count": 1 }),
+                json!({ "user.country": "nz", "plan": "pro", "count": 1 }),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn canonical_keys() -> Result<()> {
+        let input = r#"
+            {"k": {"a": 1, "b": [2]}, "n": 1}
+            {"k": {"b": [2.0], "a": 1}, "n": 1.0}
+            {"k": {"a": 1, "b": [3]}, "n": 1.5}
+        "#;
+        assert_eq!(
+            run(group(&["k", "n"], &[], &[]), input)?,
+            [
+                json!({ "k": { "a": 1, "b": [2] }, "n": 1, "count": 2 }),
+                json!({ "k": { "a": 1, "b": [3] }, "n": 1.5, "count": 1 }),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn not_a_number() {
+        let err = run(group(&[], &["plan"], &[]), RECORDS).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "record 0: cannot aggregate \"plan\": expected JSON number, not string"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::io::{BufReader, Read};
+
+use json_tools::*;
+use posix_cli_utils::*;
+use serde::{Serialize, Serializer};
+use serde_json::{de::IoRead, Value};
+
+/// Re-emit a stream of (possibly pretty-printed) JSON values compactly, one value per line.
+#[derive(Debug, Clone, Args)]
+struct Min {
+    /// Collect every value in the input into a single JSON array on one line.  If the input is a
+    /// single array, it is written as it is rather than wrapped in another array, so a
+    /// pretty-printed array is just collapsed onto one line.  Can't be used with --pretty or
+    /// --indent.
+    #[clap(long, conflicts_with_all = &["pretty", "indent"])]
+    single_line: bool,
+}
+
+#[derive(Debug, Clone, Parser)]
+struct ClArgs {
+    #[clap(flatten)]
+    input: InputOptions,
+    #[clap(flatten)]
+    options: Min,
+    #[clap(flatten)]
+    output: OutputOptions,
+}
+
+impl RunStreamJson for Min {
+    fn process_one<S>(&mut self, value: Value, output: S) -> Result<Option<S::Ok>>
+    where
+        S: Serializer,
+        S::Error: Send + Sync + 'static,
+    {
+        Ok(Some(value.serialize(output)?))
+    }
+}
+
+/// The value written with --single-line, see `Min::single_line`.
+fn single_line(mut values: Vec<Value>) -> Value {
+    if values.len() == 1 && values[0].is_array() {
+        values.pop().unwrap()
+    } else {
+        Value::Array(values)
+    }
+}
+
+fn read_all(input: impl Read) -> Result<Vec<Value>> {
+    let stream = serde_json::Deserializer::new(IoRead::new(input)).into_iter::<Value>();
+    Ok(stream.collect::<Result<_, _>>()?)
+}
+
+fn main() -> Result<()> {
+    reset_sigpipe();
+    let mut args = ClArgs::parse();
+    if !args.options.single_line {
+        return args.options.main(&args.input, &args.output);
+    }
+
+    let mut values = Vec::new();
+    args.input.for_each(|input| {
+        values.extend(match input {
+            Input::File(f) => read_all(BufReader::new(f))?,
+            Input::Stdin(i) => read_all(i.lock())?,
+        });
+        Ok(())
+    })?;
+    let count = values.len();
+    let value = single_line(values);
+    args.output.with_output(|w| {
+        args.output.serialize_to(&mut *w, &value)?;
+        writeln!(w)?;
+        Ok(())
+    })?;
+    if args.output.count {
+        eprintln!("{}", count);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const PRETTY: &str = r#"{
+  "a": [
+    1,
+    2
+  ],
+  "b": "x\ny"
+}
+[]
+  3
+"#;
+
+    #[test]
+    fn one_value_per_line() -> Result<()> {
+        let mut options = Min { single_line: false };
+        let mut output = Vec::new();
+        for value in read_all(PRETTY.as_bytes())? {
+            let mut ser = serde_json::Serializer::new(&mut output);
+            options.process_one(value, &mut ser)?;
+            output.push(b'\n');
+        }
+        assert_eq!(
+            String::from_utf8(output)?,
+            "{\"a\":[1,2],\"b\":\"x\\ny\"}\n[]\n3\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn single_line_array() -> Result<()> {
+        assert_eq!(
+            single_line(read_all(PRETTY.as_bytes())?).to_string(),
+            r#"[{"a":[1,2],"b":"x\ny"},[],3]"#
+        );
+        let array = "[\n  1,\n  [\n    2\n  ]\n]\n";
+        assert_eq!(single_line(read_all(array.as_bytes())?), json!([1, [2]]));
+        assert_eq!(single_line(read_all("{}".as_bytes())?), json!([{}]));
+        assert_eq!(single_line(Vec::new()), json!([]));
+
+        for flag in ["--pretty", "--indent=2"] {
+            assert!(ClArgs::try_parse_from(["json-min", "--single-line", flag]).is_err());
+        }
+        assert!(ClArgs::try_parse_from(["json-min", "--single-line"]).is_ok());
+        Ok(())
+    }
+}
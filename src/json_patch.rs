@@ -0,0 +1,523 @@
+use std::path::PathBuf;
+
+use clap::ArgEnum;
+use json_tools::*;
+use posix_cli_utils::*;
+use serde::{Serialize, Serializer};
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+enum OnTestFail {
+    Error,
+    Skip,
+}
+
+/// Apply a JSON Patch (RFC 6902) to each record in a JSON stream.
+#[derive(Debug, Clone, Args)]
+struct PatchArgs {
+    /// JSON Patch document to apply
+    #[clap(short = 'p', long)]
+    patch: PathBuf,
+    /// What to do with a record when a `test` operation fails: exit with an error, or output the
+    /// record unchanged.
+    #[clap(long, arg_enum, default_value = "error")]
+    on_test_fail: OnTestFail,
+    /// Don't apply the patch, only check its `test` operations against each record and report the
+    /// ones which fail.  Nothing is written to the output, and the exit status is non-zero if any
+    /// test fails.
+    #[clap(long)]
+    verify: bool,
+}
+
+#[derive(Debug, Clone, Parser)]
+struct ClArgs {
+    #[clap(flatten)]
+    input: InputOptions,
+    #[clap(flatten)]
+    options: PatchArgs,
+    #[clap(flatten)]
+    output: OutputOptions,
+}
+
+/// A parsed JSON Pointer (RFC 6901)
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pointer {
+    path: String,
+    tokens: Vec<String>,
+}
+
+impl Pointer {
+    fn parse(path: String) -> Result<Self> {
+        let tokens = match path.strip_prefix('/') {
+            Some(rest) => rest
+                .split('/')
+                .map(|t| t.replace("~1", "/").replace("~0", "~"))
+                .collect(),
+            None if path.is_empty() => Vec::new(),
+            None => bail!("JSON pointer must be empty or start with '/': {}", path),
+        };
+        Ok(Pointer { path, tokens })
+    }
+
+    /// Returns `true` if `self` points strictly inside `other`.
+    fn is_inside(&self, other: &Pointer) -> bool {
+        self.tokens.len() > other.tokens.len() && self.tokens.starts_with(&other.tokens)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operation {
+    Add { path: Pointer, value: Value },
+    Remove { path: Pointer },
+    Replace { path: Pointer, value: Value },
+    Move { from: Pointer, path: Pointer },
+    Copy { from: Pointer, path: Pointer },
+    Test { path: Pointer, value: Value },
+}
+
+impl Operation {
+    fn parse(op: Value) -> Result<Self> {
+        let mut op = op.expect_object()?;
+        let mut field = |name: &str| {
+            op.remove(name)
+                .ok_or_else(|| anyhow!("missing \"{}\" field", name))
+        };
+        let kind = field("op")?.expect_string()?;
+        let path = Pointer::parse(field("path")?.expect_string()?)?;
+        let op = match kind.as_str() {
+            "add" => Operation::Add {
+                path,
+                value: field("value")?,
+            },
+            "remove" => Operation::Remove { path },
+            "replace" => Operation::Replace {
+                path,
+                value: field("value")?,
+            },
+            "move" => Operation::Move {
+                from: Pointer::parse(field("from")?.expect_string()?)?,
+                path,
+            },
+            "copy" => Operation::Copy {
+                from: Pointer::parse(field("from")?.expect_string()?)?,
+                path,
+            },
+            "test" => Operation::Test {
+                path,
+                value: field("value")?,
+            },
+            other => bail!("unknown operation: {}", other),
+        };
+        Ok(op)
+    }
+}
+
+fn array_index(token: &str, len: usize) -> Result<usize> {
+    let canonical = token == "0" || !(token.is_empty() || token.starts_with('0'));
+    match token.parse::<usize>() {
+        Ok(i) if canonical && token.bytes().all(|b| b.is_ascii_digit()) && i < len => Ok(i),
+        _ => bail!("invalid array index: {}", token),
+    }
+}
+
+fn get<'a>(doc: &'a Value, tokens: &[String]) -> Option<&'a Value> {
+    tokens.iter().try_fold(doc, |value, token| match value {
+        Value::Object(map) => map.get(token),
+        Value::Array(items) => items.get(array_index(token, items.len()).ok()?),
+        _ => None,
+    })
+}
+
+fn get_mut<'a>(doc: &'a mut Value, tokens: &[String]) -> Option<&'a mut Value> {
+    tokens.iter().try_fold(doc, |value, token| match value {
+        Value::Object(map) => map.get_mut(token),
+        Value::Array(items) => {
+            let i = array_index(token, items.len()).ok()?;
+            items.get_mut(i)
+        }
+        _ => None,
+    })
+}
+
+/// Remove `key` from `map`, keeping the order of the remaining keys.
+fn remove_ordered(map: &mut Map<String, Value>, key: &str) -> Option<Value> {
+    let mut removed = None;
+    for (k, v) in std::mem::take(map) {
+        if k == key {
+            removed = Some(v);
+        } else {
+            map.insert(k, v);
+        }
+    }
+    removed
+}
+
+/// Compare JSON values, treating numbers as equal if they have the same numeric value.
+fn json_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a == b || a.as_f64() == b.as_f64(),
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| json_eq(a, b))
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(k, a)| matches!(b.get(k), Some(b) if json_eq(a, b)))
+        }
+        (a, b) => a == b,
+    }
+}
+
+fn parent<'a>(doc: &'a mut Value, path: &'a Pointer) -> Result<(&'a mut Value, &'a str)> {
+    let (last, parent) = path.tokens.split_last().unwrap();
+    match get_mut(doc, parent) {
+        Some(parent) => Ok((parent, last)),
+        None => bail!("path not found: {}", path.path),
+    }
+}
+
+fn add(doc: &mut Value, path: &Pointer, value: Value) -> Result<()> {
+    if path.tokens.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+    match parent(doc, path)? {
+        (Value::Object(map), key) => {
+            map.insert(key.to_string(), value);
+        }
+        (Value::Array(items), "-") => items.push(value),
+        (Value::Array(items), token) => {
+            let i = array_index(token, items.len() + 1)?;
+            items.insert(i, value);
+        }
+        (other, _) => bail!("cannot add to {} at {}", other.type_name(), path.path),
+    }
+    Ok(())
+}
+
+fn remove(doc: &mut Value, path: &Pointer) -> Result<Value> {
+    if path.tokens.is_empty() {
+        return Ok(doc.take());
+    }
+    let removed = match parent(doc, path)? {
+        (Value::Object(map), key) => remove_ordered(map, key),
+        (Value::Array(items), token) => array_index(token, items.len())
+            .ok()
+            .map(|i| items.remove(i)),
+        _ => None,
+    };
+    removed.ok_or_else(|| anyhow!("path not found: {}", path.path))
+}
+
+enum Outcome {
+    Applied,
+    /// The index of the failed `test` operation
+    TestFailed(usize),
+}
+
+struct Patch {
+    operations: Vec<Operation>,
+    on_test_fail: OnTestFail,
+    verify: bool,
+    /// The number of records read so far
+    records: usize,
+    /// The number of `test` operations which failed, with `--verify`
+    failed: usize,
+}
+
+impl Patch {
+    fn new(args: &PatchArgs) -> Result<Self> {
+        let operations = load_json(&args.patch)?
+            .expect_array()
+            .map_err(Error::from)
+            .and_then(|ops| {
+                ops.into_iter()
+                    .enumerate()
+                    .map(|(i, op)| {
+                        Operation::parse(op).with_context(|| format!("invalid operation {}", i))
+                    })
+                    .collect()
+            })
+            .with_context(|| format!("invalid JSON Patch {}", args.patch.display()))?;
+        Ok(Patch {
+            operations,
+            on_test_fail: args.on_test_fail,
+            verify: args.verify,
+            records: 0,
+            failed: 0,
+        })
+    }
+
+    /// Describe each `test` operation which fails against `doc`, without applying any of the
+    /// other operations.
+    fn failed_tests(&self, doc: &Value) -> Vec<String> {
+        let mut failed = Vec::new();
+        for (i, op) in self.operations.iter().enumerate() {
+            if let Operation::Test { path, value } = op {
+                match get(doc, &path.tokens) {
+                    Some(actual) if json_eq(actual, value) => {}
+                    Some(actual) => failed.push(format!(
+                        "test operation {} failed: expected {} at {}, found {}",
+                        i, value, path.path, actual
+                    )),
+                    None => failed.push(format!(
+                        "test operation {} failed: path not found: {}",
+                        i, path.path
+                    )),
+                }
+            }
+        }
+        failed
+    }
+
+    fn apply(&self, doc: &mut Value) -> Result<Outcome> {
+        for (i, op) in self.operations.iter().enumerate() {
+            let result = match op {
+                Operation::Add { path, value } => add(doc, path, value.clone()),
+                Operation::Remove { path } => remove(doc, path).map(drop),
+                Operation::Replace { path, value } => match get_mut(doc, &path.tokens) {
+                    Some(target) => {
+                        *target = value.clone();
+                        Ok(())
+                    }
+                    None => Err(anyhow!("path not found: {}", path.path)),
+                },
+                Operation::Move { from, path } => {
+                    if path.is_inside(from) {
+                        Err(anyhow!("cannot move {} inside itself", from.path))
+                    } else {
+                        remove(doc, from).and_then(|value| add(doc, path, value))
+                    }
+                }
+                Operation::Copy { from, path } => match get(doc, &from.tokens) {
+                    Some(value) => add(doc, path, value.clone()),
+                    None => Err(anyhow!("path not found: {}", from.path)),
+                },
+                Operation::Test { path, value } => match get(doc, &path.tokens) {
+                    Some(actual) if json_eq(actual, value) => Ok(()),
+                    _ => return Ok(Outcome::TestFailed(i)),
+                },
+            };
+            result.with_context(|| format!("failed to apply operation {}", i))?;
+        }
+        Ok(Outcome::Applied)
+    }
+
+    fn patch(&self, mut value: Value) -> Result<Value> {
+        if self.on_test_fail == OnTestFail::Skip {
+            let mut patched = value.clone();
+            return match self.apply(&mut patched)? {
+                Outcome::Applied => Ok(patched),
+                Outcome::TestFailed(_) => Ok(value),
+            };
+        }
+        match self.apply(&mut value)? {
+            Outcome::Applied => Ok(value),
+            Outcome::TestFailed(i) => bail!("test operation {} failed", i),
+        }
+    }
+}
+
+impl RunStreamJson for Patch {
+    fn process_one<S>(&mut self, value: Value, output: S) -> Result<Option<S::Ok>>
+    where
+        S: Serializer,
+        S::Error: Send + Sync + 'static,
+    {
+        let record = self.records;
+        self.records += 1;
+        if self.verify {
+            for failure in self.failed_tests(&value) {
+                eprintln!("record {}: {}", record, failure);
+                self.failed += 1;
+            }
+            return Ok(None);
+        }
+        let value = self.patch(value)?;
+        Ok(Some(value.serialize(output)?))
+    }
+}
+
+fn main() -> Result<()> {
+    reset_sigpipe();
+    let args = ClArgs::parse();
+    let mut patch = Patch::new(&args.options)?;
+    patch.main(&args.input, &args.output)?;
+    if patch.failed > 0 {
+        bail!("{} test operations failed", patch.failed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn patch(ops: Value, on_test_fail: OnTestFail) -> Patch {
+        Patch {
+            operations: ops
+                .expect_array()
+                .unwrap()
+                .into_iter()
+                .map(|op| Operation::parse(op).unwrap())
+                .collect(),
+            on_test_fail,
+            verify: false,
+            records: 0,
+            failed: 0,
+        }
+    }
+
+    fn apply(ops: Value, doc: Value) -> Result<Value> {
+        patch(ops, OnTestFail::Error).patch(doc)
+    }
+
+    #[test]
+    fn pointers() -> Result<()> {
+        assert_eq!(Pointer::parse("".into())?.tokens, Vec::<String>::new());
+        assert_eq!(
+            Pointer::parse("/a~1b/~01/".into())?.tokens,
+            ["a/b", "~1", ""]
+        );
+        assert!(Pointer::parse("a".into()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn add() -> Result<()> {
+        let doc = json!({ "foo": "bar", "baz": [1, 2] });
+        let ops = json!([
+            { "op": "add", "path": "/qux", "value": { "a": 1 } },
+            { "op": "add", "path": "/qux/b", "value": 2 },
+            { "op": "add", "path": "/baz/1", "value": "x" },
+            { "op": "add", "path": "/baz/-", "value": 3 },
+            { "op": "add", "path": "/foo", "value": ["y"] },
+        ]);
+        assert_eq!(
+            serde_json::to_string(&apply(ops, doc.clone())?)?,
+            r#"{"foo":["y"],"baz":[1,"x",2,3],"qux":{"a":1,"b":2}}"#
+        );
+        assert!(apply(
+            json!([{ "op": "add", "path": "/baz/3", "value": 1 }]),
+            doc.clone()
+        )
+        .is_err());
+        assert!(apply(
+            json!([{ "op": "add", "path": "/a/b", "value": 1 }]),
+            doc.clone()
+        )
+        .is_err());
+        assert_eq!(
+            apply(json!([{ "op": "add", "path": "", "value": 1 }]), doc)?,
+            json!(1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn remove_replace() -> Result<()> {
+        let doc = json!({ "a": 1, "b": [1, 2, 3], "c": 3 });
+        let ops = json!([
+            { "op": "remove", "path": "/a" },
+            { "op": "remove", "path": "/b/0" },
+            { "op": "replace", "path": "/b/1", "value": 4 },
+        ]);
+        assert_eq!(
+            serde_json::to_string(&apply(ops, doc.clone())?)?,
+            r#"{"b":[2,4],"c":3}"#
+        );
+        assert!(apply(json!([{ "op": "remove", "path": "/x" }]), doc.clone()).is_err());
+        assert!(apply(
+            json!([{ "op": "replace", "path": "/b/3", "value": 1 }]),
+            doc
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn move_copy() -> Result<()> {
+        let doc = json!({ "foo": { "bar": "baz", "waldo": "fred" }, "qux": { "corge": "grault" } });
+        let ops = json!([
+            { "op": "move", "from": "/foo/waldo", "path": "/qux/thud" },
+            { "op": "copy", "from": "/qux", "path": "/foo/qux" },
+        ]);
+        assert_eq!(
+            apply(ops, doc.clone())?,
+            json!({
+                "foo": { "bar": "baz", "qux": { "corge": "grault", "thud": "fred" } },
+                "qux": { "corge": "grault", "thud": "fred" },
+            })
+        );
+        assert!(apply(
+            json!([{ "op": "move", "from": "/foo", "path": "/foo/x" }]),
+            doc
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_op() -> Result<()> {
+        let doc = json!({ "a": { "b": [1, 2.0] } });
+        let ops = json!([
+            { "op": "test", "path": "/a/b", "value": [1.0, 2] },
+            { "op": "add", "path": "/c", "value": 1 },
+            { "op": "test", "path": "/c", "value": 2 },
+        ]);
+        assert!(patch(ops.clone(), OnTestFail::Error)
+            .patch(doc.clone())
+            .is_err());
+        assert_eq!(patch(ops, OnTestFail::Skip).patch(doc.clone())?, doc);
+
+        let ops = json!([
+            { "op": "test", "path": "/a/b/1", "value": 2 },
+            { "op": "remove", "path": "/a" },
+        ]);
+        assert_eq!(patch(ops, OnTestFail::Skip).patch(doc)?, json!({}));
+        Ok(())
+    }
+
+    #[test]
+    fn verify() -> Result<()> {
+        let ops = json!([
+            { "op": "test", "path": "/a", "value": 1 },
+            { "op": "remove", "path": "/a" },
+            { "op": "test", "path": "/a", "value": 1 },
+            { "op": "test", "path": "/b~1c", "value": [true] },
+        ]);
+        let mut patch = Patch {
+            verify: true,
+            ..patch(ops, OnTestFail::Error)
+        };
+        assert_eq!(
+            patch.failed_tests(&json!({ "a": 1.0, "b/c": [true] })),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            patch.failed_tests(&json!({ "a": 2 })),
+            [
+                "test operation 0 failed: expected 1 at /a, found 2",
+                "test operation 2 failed: expected 1 at /a, found 2",
+                "test operation 3 failed: path not found: /b~1c",
+            ]
+        );
+
+        for doc in [json!({ "a": 1, "b/c": [true] }), json!({ "a": 2 })] {
+            assert!(patch
+                .process_one(doc, serde_json::value::Serializer)?
+                .is_none());
+        }
+        assert_eq!((patch.records, patch.failed), (2, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn bad_operations() {
+        assert!(Operation::parse(json!({ "op": "add", "path": "/a" })).is_err());
+        assert!(Operation::parse(json!({ "op": "frobnicate", "path": "/a" })).is_err());
+        assert!(Operation::parse(json!({ "op": "move", "path": "/a" })).is_err());
+        assert!(Operation::parse(json!(["add"])).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+
+use json_tools::*;
+use posix_cli_utils::*;
+use serde::{Serialize, Serializer};
+use serde_json::{Map, Value};
+
+/// Keep or drop keys from each object in a JSON stream, leaving the rest of the record as is.
+#[derive(Debug, Clone, Args)]
+struct ProjectArgs {
+    /// Comma-separated list of keys to keep.  Nested keys such as `a.b` keep only `b` inside `a`.
+    #[clap(short = 'k', long, use_value_delimiter = true, conflicts_with = "drop")]
+    keep: Vec<String>,
+    /// Comma-separated list of keys to remove.  Nested keys such as `a.b` remove only `b` inside `a`.
+    #[clap(short = 'x', long, use_value_delimiter = true)]
+    drop: Vec<String>,
+    /// Separator used in nested keys
+    #[clap(short = 'd', default_value = ".")]
+    sep: String,
+}
+
+#[derive(Debug, Clone, Parser)]
+struct ClArgs {
+    #[clap(flatten)]
+    input: InputOptions,
+    #[clap(flatten)]
+    options: ProjectArgs,
+    #[clap(flatten)]
+    output: OutputOptions,
+}
+
+/// A set of (possibly nested) keys.  A key maps to `None` if it is selected in its entirety.
+#[derive(Debug, Clone, Default)]
+struct KeyTree(HashMap<String, Option<KeyTree>>);
+
+impl KeyTree {
+    fn new<'a>(keys: impl IntoIterator<Item = &'a String>, sep: &str) -> Self {
+        let mut tree = KeyTree::default();
+        for key in keys {
+            let mut path = key.split(sep);
+            tree.insert(path.next().unwrap(), path);
+        }
+        tree
+    }
+
+    fn insert<'a>(&mut self, key: &str, mut rest: impl Iterator<Item = &'a str>) {
+        match rest.next() {
+            None => {
+                self.0.insert(key.to_string(), None);
+            }
+            Some(next) => {
+                let subtree = self
+                    .0
+                    .entry(key.to_string())
+                    .or_insert_with(|| Some(KeyTree::default()));
+                if let Some(subtree) = subtree {
+                    subtree.insert(next, rest);
+                }
+            }
+        }
+    }
+}
+
+enum Project {
+    Keep(KeyTree),
+    Drop(KeyTree),
+}
+
+fn keep_keys(map: &mut Map<String, Value>, keys: &KeyTree) {
+    for (k, mut v) in std::mem::take(map) {
+        match keys.0.get(&k) {
+            None => {}
+            Some(None) => {
+                map.insert(k, v);
+            }
+            Some(Some(subtree)) => {
+                if let Value::Object(inner) = &mut v {
+                    keep_keys(inner, subtree);
+                    if !inner.is_empty() {
+                        map.insert(k, v);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn drop_keys(map: &mut Map<String, Value>, keys: &KeyTree) {
+    for (k, mut v) in std::mem::take(map) {
+        match keys.0.get(&k) {
+            Some(None) => {}
+            Some(Some(subtree)) => {
+                if let Value::Object(inner) = &mut v {
+                    drop_keys(inner, subtree);
+                }
+                map.insert(k, v);
+            }
+            None => {
+                map.insert(k, v);
+            }
+        }
+    }
+}
+
+impl Project {
+    fn new(args: &ProjectArgs) -> Self {
+        if args.keep.is_empty() {
+            Project::Drop(KeyTree::new(&args.drop, &args.sep))
+        } else {
+            Project::Keep(KeyTree::new(&args.keep, &args.sep))
+        }
+    }
+
+    fn project(&self, value: &mut Value) {
+        if let Value::Object(map) = value {
+            match self {
+                Project::Keep(keys) => keep_keys(map, keys),
+                Project::Drop(keys) => drop_keys(map, keys),
+            }
+        }
+    }
+}
+
+impl RunStreamJson for Project {
+    fn process_one<S>(&mut self, mut value: Value, output: S) -> Result<Option<S::Ok>>
+    where
+        S: Serializer,
+        S::Error: Send + Sync + 'static,
+    {
+        self.project(&mut value);
+        Ok(Some(value.serialize(output)?))
+    }
+}
+
+fn main() -> Result<()> {
+    reset_sigpipe();
+    let args = ClArgs::parse();
+    Project::new(&args.options).main(&args.input, &args.output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn project(keep: &[&str], drop: &[&str], mut value: Value) -> String {
+        let args = ProjectArgs {
+            keep: keep.iter().map(|k| k.to_string()).collect(),
+            drop: drop.iter().map(|k| k.to_string()).collect(),
+            sep: ".".to_string(),
+        };
+        Project::new(&args).project(&mut value);
+        serde_json::to_string(&value).unwrap()
+    }
+
+    fn record() -> Value {
+        json!({ "c": 1, "a": { "x": 1, "b": 2 }, "b": [1, 2], "d": "d" })
+    }
+
+    #[test]
+    fn keep() {
+        assert_eq!(project(&["b", "c"], &[], record()), r#"{"c":1,"b":[1,2]}"#);
+        assert_eq!(
+            project(&["a.b", "d"], &[], record()),
+            r#"{"a":{"b":2},"d":"d"}"#
+        );
+        assert_eq!(
+            project(&["a.b", "a"], &[], record()),
+            r#"{"a":{"x":1,"b":2}}"#
+        );
+        assert_eq!(project(&["a.z", "b.0", "c"], &[], record()), r#"{"c":1}"#);
+    }
+
+    #[test]
+    fn drop() {
+        assert_eq!(
+            project(&[], &["b", "c"], record()),
+            r#"{"a":{"x":1,"b":2},"d":"d"}"#
+        );
+        assert_eq!(
+            project(&[], &["a.b", "d", "b.0", "z"], record()),
+            r#"{"c":1,"a":{"x":1},"b":[1,2]}"#
+        );
+        assert_eq!(
+            project(&[], &["a.x", "a.b"], record()),
+            r#"{"c":1,"a":{},"b":[1,2],"d":"d"}"#
+        );
+    }
+
+    #[test]
+    fn non_objects() {
+        assert_eq!(project(&["a"], &[], json!([1, 2])), "[1,2]");
+        assert_eq!(project(&[], &["a"], json!("a")), r#""a""#);
+    }
+}
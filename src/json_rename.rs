@@ -0,0 +1,209 @@
+use std::{collections::HashMap, str::FromStr};
+
+use indexmap::IndexMap;
+use json_tools::*;
+use posix_cli_utils::*;
+use serde::{Serialize, Serializer};
+use serde_json::{Map, Value};
+
+/// A single `OLD=NEW` rename
+#[derive(Debug, Clone)]
+struct Mapping {
+    from: String,
+    to: String,
+}
+
+impl FromStr for Mapping {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok(Mapping {
+                from: from.to_string(),
+                to: to.to_string(),
+            }),
+            _ => bail!("expected a mapping of the form `OLD=NEW`: {}", s),
+        }
+    }
+}
+
+/// Rename keys in each object of a JSON stream, keeping their position in the object.
+#[derive(Debug, Clone, Args)]
+struct RenameArgs {
+    /// Rename the key OLD to NEW.  OLD may be a nested key such as `foo.bar`, in which case NEW is
+    /// the new name for `bar` inside `foo`.
+    #[clap(short = 'm', long = "map", value_name = "OLD=NEW")]
+    mappings: Vec<Mapping>,
+    /// Print a warning instead of failing when a renamed key collides with another key.  The value
+    /// which comes last in the object is kept.
+    #[clap(long)]
+    warn_collisions: bool,
+    /// Separator used in nested keys
+    #[clap(short = 'd', default_value = ".")]
+    sep: String,
+}
+
+#[derive(Debug, Clone, Parser)]
+struct ClArgs {
+    #[clap(flatten)]
+    input: InputOptions,
+    #[clap(flatten)]
+    options: RenameArgs,
+    #[clap(flatten)]
+    output: OutputOptions,
+}
+
+struct Rename {
+    /// Renames grouped by the path to the object containing the key, deepest paths first.
+    renames: Vec<(Vec<String>, HashMap<String, String>)>,
+    warn_collisions: bool,
+}
+
+impl Rename {
+    fn new(args: &RenameArgs) -> Result<Self> {
+        let mut groups: IndexMap<Vec<String>, HashMap<String, String>> = IndexMap::new();
+        for m in &args.mappings {
+            let mut path: Vec<String> = m.from.split(&*args.sep).map(String::from).collect();
+            let key = path.pop().unwrap();
+            if groups
+                .entry(path)
+                .or_default()
+                .insert(key, m.to.clone())
+                .is_some()
+            {
+                bail!("key {} is renamed more than once", m.from);
+            }
+        }
+        let mut renames: Vec<_> = groups.into_iter().collect();
+        renames.sort_by_key(|(path, _)| std::cmp::Reverse(path.len()));
+        Ok(Rename {
+            renames,
+            warn_collisions: args.warn_collisions,
+        })
+    }
+
+    fn rename_keys(
+        &self,
+        map: &mut Map<String, Value>,
+        renames: &HashMap<String, String>,
+    ) -> Result<()> {
+        if !renames.keys().any(|k| map.contains_key(k)) {
+            return Ok(());
+        }
+        for (key, value) in std::mem::take(map) {
+            let key = match renames.get(&key) {
+                Some(new) => new.clone(),
+                None => key,
+            };
+            if map.contains_key(&key) {
+                if self.warn_collisions {
+                    eprintln!(
+                        "warning: key {:?} occurs more than once after renaming",
+                        key
+                    );
+                } else {
+                    bail!("key {:?} occurs more than once after renaming", key);
+                }
+            }
+            map.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn rename(&self, value: &mut Value) -> Result<()> {
+        for (path, renames) in &self.renames {
+            if let Some(Value::Object(map)) = value.get_path_mut(path.iter().map(String::as_str)) {
+                self.rename_keys(map, renames)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RunStreamJson for Rename {
+    fn process_one<S>(&mut self, mut value: Value, output: S) -> Result<Option<S::Ok>>
+    where
+        S: Serializer,
+        S::Error: Send + Sync + 'static,
+    {
+        self.rename(&mut value)?;
+        Ok(Some(value.serialize(output)?))
+    }
+}
+
+fn main() -> Result<()> {
+    reset_sigpipe();
+    let args = ClArgs::parse();
+    Rename::new(&args.options)?.main(&args.input, &args.output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rename(mappings: &[&str], warn_collisions: bool) -> Rename {
+        Rename::new(&RenameArgs {
+            mappings: mappings.iter().map(|m| m.parse().unwrap()).collect(),
+            warn_collisions,
+            sep: ".".to_string(),
+        })
+        .unwrap()
+    }
+
+    fn run(r: &Rename, mut value: Value) -> Result<String> {
+        r.rename(&mut value)?;
+        Ok(serde_json::to_string(&value)?)
+    }
+
+    #[test]
+    fn preserves_position() -> Result<()> {
+        let r = rename(&["b=x", "missing=y"], false);
+        let x = run(&r, json!({ "a": 1, "b": 2, "c": 3 }))?;
+        assert_eq!(x, r#"{"a":1,"x":2,"c":3}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn nested() -> Result<()> {
+        let r = rename(&["foo.bar=baz", "foo=f", "l.0.a=b"], false);
+        let x = run(
+            &r,
+            json!({ "foo": { "bar": 1, "z": 2 }, "l": [{ "a": 3 }, { "a": 4 }] }),
+        )?;
+        assert_eq!(x, r#"{"f":{"baz":1,"z":2},"l":[{"b":3},{"a":4}]}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn non_objects() -> Result<()> {
+        let r = rename(&["a=b", "a.b=c"], false);
+        assert_eq!(run(&r, json!([1, 2]))?, "[1,2]");
+        assert_eq!(run(&r, json!({ "a": 1 }))?, r#"{"b":1}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn collisions() -> Result<()> {
+        let r = rename(&["a=c", "b=c"], false);
+        assert!(run(&r, json!({ "a": 1, "b": 2 })).is_err());
+        assert!(run(&r, json!({ "a": 1, "c": 2 })).is_err());
+        assert_eq!(run(&r, json!({ "b": 2, "d": 2 }))?, r#"{"c":2,"d":2}"#);
+
+        let r = rename(&["a=c", "b=c"], true);
+        assert_eq!(run(&r, json!({ "a": 1, "b": 2 }))?, r#"{"c":2}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn bad_mappings() {
+        assert!("a".parse::<Mapping>().is_err());
+        assert!("=a".parse::<Mapping>().is_err());
+        let args = RenameArgs {
+            mappings: vec!["a=b".parse().unwrap(), "a=c".parse().unwrap()],
+            warn_collisions: false,
+            sep: ".".to_string(),
+        };
+        assert!(Rename::new(&args).is_err());
+    }
+}
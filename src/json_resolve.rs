@@ -3,15 +3,20 @@ use json_tools::*;
 use posix_cli_utils::*;
 use regex::Regex;
 use serde::{Serialize, Serializer};
-use serde_json::Value;
-use std::path::PathBuf;
+use serde_json::{Map, Value};
+use std::{
+    borrow::Cow,
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
 
 #[derive(Debug, Clone, Args)]
 struct Resolve {
     /// Print error messages to STDERR when files match the regex but cannot be opened
     #[clap(short = 'v')]
     verbose: bool,
-    /// Set the regex used to identify strings as filenames
+    /// Set the regex used to identify strings as filenames.  References of the form `FILE#/json/pointer`
+    /// are matched on `FILE` and replaced by the subtree the pointer selects.
     #[clap(short='m', parse(try_from_str=Regex::new), default_value=r"\.json$")]
     regex: Regex,
     /// Enable recursive resolution
@@ -21,14 +26,44 @@ struct Resolve {
     /// is the file's parent directory.  Otherwise the search path is the current working directory.
     #[clap(short = 'd')]
     directories: Vec<PathBuf>,
+    /// When a value in an object is replaced by an object, merge its keys into the parent object
+    /// instead of nesting it under the key.  Keys already in the parent win over merged keys, and
+    /// merged keys are added after the parent's keys.
+    #[clap(long)]
+    inline_merge: bool,
+    /// Also treat object keys matching the regex as references.  The key is replaced by the keys
+    /// of the object it refers to, as with --inline-merge, and its value is dropped.  Keys which
+    /// refer to something other than an object, or can't be loaded, are left as they are.
+    #[clap(long)]
+    expand_keys: bool,
+    /// Don't resolve anything, instead print every reference and the file it would be loaded
+    /// from (or NOT FOUND), separated by a tab.  References inside referenced files are not
+    /// listed, even with -r.
+    #[clap(long)]
+    list_refs: bool,
+    /// Write the result back to INPUT instead of STDOUT.  INPUT is only replaced once the whole
+    /// result has been written.
+    #[clap(
+        short = 'i',
+        long,
+        conflicts_with_all = &["output-file", "input-dir", "list-refs"]
+    )]
+    in_place: bool,
+    /// Replace `${VAR}` in references with the value of the environment variable VAR before
+    /// looking for the file, eg. `${DATA_DIR}/config.json`.  References using a variable which
+    /// isn't set are left as they are.
+    #[clap(long)]
+    expand_env: bool,
 }
 
 #[derive(Debug, Clone, Parser)]
 struct ClArgs {
-    /// Input JSON file (defaults to STDIN)
-    input: Option<PathBuf>,
+    #[clap(flatten)]
+    input: InputOptions,
     #[clap(flatten)]
     options: Resolve,
+    #[clap(flatten)]
+    output: OutputOptions,
 }
 
 impl Resolve {
@@ -39,20 +74,83 @@ impl Resolve {
                 return;
             }
 
+            Value::Object(map) if self.inline_merge || self.expand_keys => {
+                self.resolve_merge(map);
+                return;
+            }
+
             Value::Object(map) => {
                 map.values_mut().for_each(|v| self.resolve(v));
                 return;
             }
 
-            Value::String(s) if self.regex.is_match(s) => &*s,
+            Value::String(s) => s,
 
             _ => return,
         };
 
+        if let Some(replacement) = self.lookup(filename) {
+            *val = replacement;
+        }
+    }
+
+    /// Resolve the values in `map`, merging objects which replace a value (with --inline-merge)
+    /// or a key (with --expand-keys) into `map`.
+    fn resolve_merge(&self, map: &mut Map<String, Value>) {
+        let mut merged = Vec::new();
+        for (key, mut value) in std::mem::take(map) {
+            if self.expand_keys {
+                if let Some(inner) = self.expand_key(&key) {
+                    merged.push(inner);
+                    continue;
+                }
+            }
+            let replacement = match &value {
+                Value::String(s) if self.inline_merge => self.lookup(s),
+                _ => None,
+            };
+            match replacement {
+                Some(Value::Object(inner)) => merged.push(inner),
+                Some(replacement) => {
+                    map.insert(key, replacement);
+                }
+                None => {
+                    self.resolve(&mut value);
+                    map.insert(key, value);
+                }
+            }
+        }
+        for (key, value) in merged.into_iter().flatten() {
+            map.entry(key).or_insert(value);
+        }
+    }
+
+    /// Load the object the key `key` refers to, or `None` if it isn't a reference to an object.
+    fn expand_key(&self, key: &str) -> Option<Map<String, Value>> {
+        match self.lookup(key)? {
+            Value::Object(inner) => Some(inner),
+            other => {
+                if self.verbose {
+                    eprintln!(
+                        "{} refers to {}, not an object, so it isn't expanded\n",
+                        key,
+                        other.type_name()
+                    );
+                }
+                None
+            }
+        }
+    }
+
+    /// Load the value `filename` refers to, or `None` if it isn't a reference or can't be loaded.
+    fn lookup(&self, filename: &str) -> Option<Value> {
+        let (filename, fragment) = self.parse_reference(filename)?;
+        let filename = self.file_name(filename)?;
+
         let mut replacement = None;
         for d in &self.directories {
-            let p = d.join(filename);
-            match load_json(p) {
+            let p = d.join(&*filename);
+            match load_json(&p).and_then(|v| select_fragment(v, fragment, &p)) {
                 Ok(v) => {
                     replacement = Some(v);
                     break;
@@ -64,24 +162,134 @@ impl Resolve {
                 }
             }
         }
-        if let Some(mut replacement) = replacement {
+        if let Some(replacement) = &mut replacement {
             if self.recursion {
-                self.resolve(&mut replacement);
+                self.resolve(replacement);
             }
-            *val = replacement;
         }
+        replacement
+    }
+
+    /// Split a reference into the file name and fragment, or `None` if it isn't a reference.
+    fn parse_reference<'a>(&self, s: &'a str) -> Option<(&'a str, Option<&'a str>)> {
+        let (filename, fragment) = match s.split_once('#') {
+            Some((filename, fragment)) => (filename, Some(fragment)),
+            None => (s, None),
+        };
+        if self.regex.is_match(filename) {
+            Some((filename, fragment))
+        } else {
+            None
+        }
+    }
+
+    /// The name of the file to look for, with environment variables expanded if --expand-env is
+    /// given, or `None` if a variable isn't set.
+    fn file_name<'a>(&self, filename: &'a str) -> Option<Cow<'a, str>> {
+        if !self.expand_env {
+            return Some(Cow::Borrowed(filename));
+        }
+        match expand_env(filename) {
+            Ok(filename) => Some(filename),
+            Err(e) => {
+                if self.verbose {
+                    eprintln!("{:?}\n", e);
+                }
+                None
+            }
+        }
+    }
+
+    /// Write every reference in each record of `input` to `output`, see --list-refs.  Returns the
+    /// number of records read.
+    fn list_all_refs(&self, input: impl Read, output: &mut impl Write) -> Result<usize> {
+        let mut count = 0;
+        for value in serde_json::Deserializer::from_reader(input).into_iter::<Value>() {
+            self.list_refs(&value?, output)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Write every reference in `val` to `output`, with the file it refers to.
+    fn list_refs(&self, val: &Value, output: &mut impl Write) -> io::Result<()> {
+        match val {
+            Value::Array(list) => list.iter().try_for_each(|v| self.list_refs(v, output)),
+            Value::Object(map) => map.iter().try_for_each(|(k, v)| {
+                if self.expand_keys {
+                    self.list_ref(k, output)?;
+                }
+                self.list_refs(v, output)
+            }),
+            Value::String(s) => self.list_ref(s, output),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write `s` to `output` with the file it refers to, if it's a reference.
+    fn list_ref(&self, s: &str, output: &mut impl Write) -> io::Result<()> {
+        let filename = match self.parse_reference(s) {
+            Some((filename, _)) => filename,
+            None => return Ok(()),
+        };
+        let path = self.file_name(filename).and_then(|filename| {
+            self.directories
+                .iter()
+                .map(|d| d.join(&*filename))
+                .find(|p| p.is_file())
+        });
+        match path {
+            Some(path) => writeln!(output, "{}\t{}", s, path.display()),
+            None => writeln!(output, "{}\tNOT FOUND", s),
+        }
+    }
+}
+
+/// Replace each `${VAR}` in `s` with the value of the environment variable VAR.
+fn expand_env(s: &str) -> Result<Cow<'_, str>> {
+    if !s.contains("${") {
+        return Ok(Cow::Borrowed(s));
+    }
+    let mut expanded = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("unterminated `${{` in {:?}", s))?;
+        let name = &rest[start + 2..start + end];
+        match std::env::var(name) {
+            Ok(value) => expanded.push_str(&value),
+            Err(_) => bail!("environment variable {:?} in {:?} isn't set", name, s),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(Cow::Owned(expanded))
+}
+
+/// Select the subtree of `value` given by the JSON Pointer `fragment`, if any.
+fn select_fragment(mut value: Value, fragment: Option<&str>, path: &Path) -> Result<Value> {
+    match fragment {
+        None => Ok(value),
+        Some(pointer) => value.pointer_mut(pointer).map(Value::take).ok_or_else(|| {
+            anyhow!(
+                "failed to resolve JSON pointer {:?} in {}",
+                pointer,
+                path.display()
+            )
+        }),
     }
 }
 
 impl RunStreamJson for Resolve {
-    fn process_one<S>(&mut self, mut value: Value, output: S) -> Result<()>
+    fn process_one<S>(&mut self, mut value: Value, output: S) -> Result<Option<S::Ok>>
     where
         S: Serializer,
         S::Error: Send + Sync + 'static,
     {
         self.resolve(&mut value);
-        value.serialize(output)?;
-        Ok(())
+        Ok(Some(value.serialize(output)?))
     }
 }
 
@@ -89,10 +297,17 @@ fn main() -> Result<()> {
     reset_sigpipe();
     let mut args = ClArgs::parse();
 
-    let input = Input::default_stdin(args.input.as_ref())?;
+    if args.options.in_place {
+        match &args.input.path {
+            Some(path) => args.output.in_place = Some(path.clone()),
+            None => bail!("cannot edit stdin in place"),
+        }
+    }
 
     if args.options.directories.is_empty() {
-        if let Some(ref filename) = args.input {
+        if let Some(ref dir) = args.input.input_dir {
+            args.options.directories.push(dir.clone());
+        } else if let Some(ref filename) = args.input.path {
             args.options
                 .directories
                 .push(filename.parent().unwrap().to_path_buf());
@@ -101,13 +316,32 @@ fn main() -> Result<()> {
         }
     }
 
-    args.options.main(input)
+    if args.options.list_refs {
+        let options = &args.options;
+        let count = args.output.with_output(|mut w| {
+            let mut count = 0;
+            args.input.for_each(|input| {
+                count += match input {
+                    Input::File(f) => options.list_all_refs(BufReader::new(f), &mut w)?,
+                    Input::Stdin(i) => options.list_all_refs(i.lock(), &mut w)?,
+                };
+                Ok(())
+            })?;
+            Ok(count)
+        })?;
+        if args.output.count {
+            eprintln!("{}", count);
+        }
+        return Ok(());
+    }
+
+    args.options.main(&args.input, &args.output)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::path::Path;
+    use serde_json::json;
 
     fn options() -> Resolve {
         Resolve {
@@ -115,6 +349,11 @@ mod tests {
             regex: Regex::new(r"\.json$").unwrap(),
             recursion: false,
             directories: vec!["tests/".into()],
+            inline_merge: false,
+            expand_keys: false,
+            list_refs: false,
+            in_place: false,
+            expand_env: false,
         }
     }
 
@@ -162,4 +401,173 @@ mod tests {
         assert_eq!(x, correct);
         Ok(())
     }
+
+    #[test]
+    #[cfg(feature = "precise-numbers")]
+    fn precise_numbers() -> Result<()> {
+        let o = options();
+        let mut x = serde_json::json!({ "p": "precise.json" });
+        o.resolve(&mut x);
+        assert_eq!(
+            serde_json::to_string(&x)?,
+            r#"{"p":{"int":1234567890123456789012345,"dec":3.14159265358979323846264338327}}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn whole_file_reference() {
+        let o = options();
+        let mut x = json!({ "c": "c.json", "d": "d.json#" });
+        o.resolve(&mut x);
+        assert_eq!(
+            x,
+            json!({ "c": [1, 2, 3], "d": { "foo": "bar", "c": "c.json" } })
+        );
+    }
+
+    #[test]
+    fn fragment_reference() {
+        let mut o = options();
+        let mut x = json!({ "a": "d.json#/foo", "b": "c.json#/1", "c": "d.json#/c" });
+        o.resolve(&mut x);
+        assert_eq!(x, json!({ "a": "bar", "b": 2, "c": "c.json" }));
+
+        o.recursion = true;
+        let mut x = json!({ "c": "d.json#/c" });
+        o.resolve(&mut x);
+        assert_eq!(x, json!({ "c": [1, 2, 3] }));
+    }
+
+    #[test]
+    fn bad_fragment() {
+        let o = options();
+        let mut x = json!({ "a": "d.json#/missing", "b": "d.json#foo" });
+        let correct = x.clone();
+        o.resolve(&mut x);
+        assert_eq!(x, correct);
+    }
+
+    #[test]
+    fn inline_merge() {
+        let mut o = Resolve {
+            inline_merge: true,
+            ..options()
+        };
+        let to_string = |x: &Value| serde_json::to_string(x).unwrap();
+
+        let mut x =
+            json!({ "foo": "x", "config": "d.json", "c": "c.json", "n": [{ "d": "d.json" }] });
+        o.resolve(&mut x);
+        assert_eq!(
+            to_string(&x),
+            r#"{"foo":"x","c":[1,2,3],"n":[{"foo":"bar","c":"c.json"}]}"#
+        );
+
+        let mut x = json!({ "config": "d.json#", "x": "d.json#/foo" });
+        o.resolve(&mut x);
+        assert_eq!(to_string(&x), r#"{"x":"bar","foo":"bar","c":"c.json"}"#);
+
+        o.recursion = true;
+        let mut x = json!({ "config": "d.json" });
+        o.resolve(&mut x);
+        assert_eq!(to_string(&x), r#"{"foo":"bar","c":[1,2,3]}"#);
+    }
+
+    #[test]
+    fn expand_keys() {
+        let mut o = Resolve {
+            expand_keys: true,
+            ..options()
+        };
+        let to_string = |x: &Value| serde_json::to_string(x).unwrap();
+
+        let mut x = json!({
+            "x": "c.json",
+            "d.json": null,
+            "c.json": null,
+            "d.json#/foo": 1,
+            "missing.json": 2,
+            "foo": "keep",
+            "n": { "d.json#": {} },
+        });
+        o.resolve(&mut x);
+        assert_eq!(
+            to_string(&x),
+            r#"{"x":[1,2,3],"c.json":null,"d.json#/foo":1,"missing.json":2,"foo":"keep","n":{"foo":"bar","c":"c.json"},"c":"c.json"}"#
+        );
+
+        o.recursion = true;
+        let mut x = json!({ "d.json": true });
+        o.resolve(&mut x);
+        assert_eq!(to_string(&x), r#"{"foo":"bar","c":[1,2,3]}"#);
+    }
+
+    #[test]
+    fn env_references() -> Result<()> {
+        std::env::set_var("JSON_TOOLS_TEST_NAME", "c");
+        std::env::set_var(
+            "JSON_TOOLS_TEST_DIR",
+            std::env::current_dir()?.join("tests"),
+        );
+        let mut o = Resolve {
+            expand_env: true,
+            ..options()
+        };
+        o.directories[0] = "src/".into();
+        let mut x = json!({
+            "a": "${JSON_TOOLS_TEST_DIR}/${JSON_TOOLS_TEST_NAME}.json",
+            "b": "${JSON_TOOLS_TEST_DIR}/d.json#/foo",
+            "c": "${JSON_TOOLS_TEST_UNSET}/c.json",
+            "d": "${JSON_TOOLS_TEST_DIR/c.json",
+        });
+        let correct = json!({
+            "a": [1, 2, 3],
+            "b": "bar",
+            "c": "${JSON_TOOLS_TEST_UNSET}/c.json",
+            "d": "${JSON_TOOLS_TEST_DIR/c.json",
+        });
+        o.resolve(&mut x);
+        assert_eq!(x, correct);
+
+        o.expand_env = false;
+        let mut x = json!({ "a": "${JSON_TOOLS_TEST_DIR}/c.json" });
+        o.resolve(&mut x);
+        assert_eq!(x, json!({ "a": "${JSON_TOOLS_TEST_DIR}/c.json" }));
+        Ok(())
+    }
+
+    #[test]
+    fn list_refs() -> Result<()> {
+        let mut o = options();
+        o.directories.insert(0, "src/".into());
+        let x = json!({ "a": ["c.json", "missing.json#/x"], "b": { "d": "d.json#/foo" }, "c": 1 });
+        let mut output = Vec::new();
+        o.list_refs(&x, &mut output)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "c.json\ttests/c.json\nmissing.json#/x\tNOT FOUND\nd.json#/foo\ttests/d.json\n"
+        );
+
+        let input = r#"{"a": "c.json"} ["d.json#/foo", 1]"#;
+        let mut output = Vec::new();
+        assert_eq!(o.list_all_refs(input.as_bytes(), &mut output)?, 2);
+        assert_eq!(
+            String::from_utf8(output)?,
+            "c.json\ttests/c.json\nd.json#/foo\ttests/d.json\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn in_place_list_refs() -> Result<()> {
+        let path = std::env::temp_dir().join("json-tools-test-resolve-list-refs.json");
+        std::fs::write(&path, "{\"a\": \"c.json\"}\n")?;
+        let args = ["json-resolve", "--list-refs", "-i", path.to_str().unwrap()];
+        let err = ClArgs::try_parse_from(args).unwrap_err();
+        assert_eq!(err.kind(), clap::ErrorKind::ArgumentConflict);
+        assert_eq!(std::fs::read_to_string(&path)?, "{\"a\": \"c.json\"}\n");
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,244 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufReader, Read},
+    str::FromStr,
+};
+
+use json_tools::*;
+use posix_cli_utils::*;
+use serde::{Serialize, Serializer};
+use serde_json::{de::IoRead, Value};
+
+/// Which of the records with the same value to keep, see `--keep`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Keep {
+    First,
+    Last,
+    All,
+}
+
+impl FromStr for Keep {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "first" => Ok(Keep::First),
+            "last" => Ok(Keep::Last),
+            "all" => Ok(Keep::All),
+            _ => bail!("expected `first`, `last` or `all`: {}", s),
+        }
+    }
+}
+
+/// Only output records which haven't been seen before.
+#[derive(Debug, Clone, Args)]
+struct Unique {
+    /// Compare records by the value at KEY (eg. `a.b`) instead of the whole record.  Records
+    /// without KEY are always written.
+    #[clap(short = 'k', long, value_name = "KEY")]
+    key: Option<String>,
+    /// Separator used to split KEY into nested keys and array indices
+    #[clap(short = 'd', default_value = ".")]
+    sep: String,
+    /// Which of the records with the same value to write, either `first`, `last` or `all`.  With
+    /// `last`, every record is read before anything is written, and the records are written in
+    /// the order they were read.  With `all`, every record is written, and records with the same
+    /// value as an earlier one get the key `"_duplicate": true` (so they must be objects).
+    #[clap(long, value_name = "WHICH", default_value = "first")]
+    keep: Keep,
+    /// The values seen so far, with --keep first or all
+    #[clap(skip)]
+    seen: HashSet<String>,
+}
+
+#[derive(Debug, Clone, Parser)]
+struct ClArgs {
+    #[clap(flatten)]
+    input: InputOptions,
+    #[clap(flatten)]
+    options: Unique,
+    #[clap(flatten)]
+    output: OutputOptions,
+}
+
+impl Unique {
+    /// The value records are compared by, or `None` if the record doesn't have `--key`.
+    fn dedup_key(&self, value: &Value) -> Option<String> {
+        let value = match &self.key {
+            Some(key) => value.get_path(key.split(&*self.sep))?,
+            None => value,
+        };
+        let mut buf = String::new();
+        write_canonical(&mut buf, value);
+        Some(buf)
+    }
+
+    /// Drop every record which has the same value as a later one.
+    fn keep_last(&self, values: Vec<Value>) -> Vec<Value> {
+        let keys: Vec<_> = values.iter().map(|v| self.dedup_key(v)).collect();
+        let mut last = HashMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            if let Some(key) = key {
+                last.insert(key, i);
+            }
+        }
+        values
+            .into_iter()
+            .zip(&keys)
+            .enumerate()
+            .filter(|(i, (_, key))| match key {
+                Some(key) => last[key] == *i,
+                None => true,
+            })
+            .map(|(_, (value, _))| value)
+            .collect()
+    }
+}
+
+impl RunStreamJson for Unique {
+    fn process_one<S>(&mut self, mut value: Value, output: S) -> Result<Option<S::Ok>>
+    where
+        S: Serializer,
+        S::Error: Send + Sync + 'static,
+    {
+        if let Some(key) = self.dedup_key(&value) {
+            if !self.seen.insert(key) {
+                match (self.keep, &mut value) {
+                    (Keep::All, Value::Object(object)) => {
+                        object.insert("_duplicate".to_string(), Value::Bool(true));
+                    }
+                    (Keep::All, other) => {
+                        bail!(
+                            "cannot mark a duplicate {} with `_duplicate`",
+                            other.type_name()
+                        )
+                    }
+                    _ => return Ok(None),
+                }
+            }
+        }
+        Ok(Some(value.serialize(output)?))
+    }
+}
+
+fn read_all(input: impl Read) -> Result<Vec<Value>> {
+    let stream = serde_json::Deserializer::new(IoRead::new(input)).into_iter::<Value>();
+    Ok(stream.collect::<Result<_, _>>()?)
+}
+
+fn main() -> Result<()> {
+    reset_sigpipe();
+    let mut args = ClArgs::parse();
+    if args.options.keep != Keep::Last {
+        return args.options.main(&args.input, &args.output);
+    }
+
+    let mut values = Vec::new();
+    args.input.for_each(|input| {
+        values.extend(match input {
+            Input::File(f) => read_all(BufReader::new(f))?,
+            Input::Stdin(i) => read_all(i.lock())?,
+        });
+        Ok(())
+    })?;
+    let count = values.len();
+    let values = args.options.keep_last(values);
+    args.output.with_output(|w| {
+        for value in &values {
+            args.output.serialize_to(&mut *w, value)?;
+            writeln!(w)?;
+        }
+        Ok(())
+    })?;
+    if args.output.count {
+        eprintln!("{}", count);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn options(key: Option<&str>) -> Unique {
+        Unique {
+            key: key.map(String::from),
+            sep: ".".to_string(),
+            keep: Keep::First,
+            seen: HashSet::new(),
+        }
+    }
+
+    fn records() -> Vec<Value> {
+        vec![
+            json!({ "id": 1, "a": { "x": 1, "y": [1, "2"] } }),
+            json!({ "a": { "y": [1, "2"], "x": 1 }, "id": 1 }),
+            json!({ "id": 2, "a": { "x": 1, "y": [1, 2] } }),
+            json!({ "id": 3, "a": { "x": 2 } }),
+            json!({ "id": 4 }),
+            json!({ "id": 5 }),
+        ]
+    }
+
+    fn keep_first(options: &mut Unique, values: Vec<Value>) -> Result<Vec<Value>> {
+        let mut kept = Vec::new();
+        for v in values {
+            kept.extend(options.process_one(v, serde_json::value::Serializer)?);
+        }
+        Ok(kept)
+    }
+
+    fn ids(values: &[Value]) -> Vec<&Value> {
+        values.iter().map(|v| &v["id"]).collect()
+    }
+
+    #[test]
+    fn whole_records() -> Result<()> {
+        let kept = keep_first(&mut options(None), records())?;
+        assert_eq!(ids(&kept), [1, 2, 3, 4, 5]);
+        assert_eq!(kept[0], records()[0]);
+
+        let kept = options(None).keep_last(records());
+        assert_eq!(ids(&kept), [1, 2, 3, 4, 5]);
+        assert_eq!(kept[0], records()[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn by_key() -> Result<()> {
+        let kept = keep_first(&mut options(Some("a.x")), records())?;
+        assert_eq!(ids(&kept), [1, 3, 4, 5]);
+        let kept = options(Some("a.x")).keep_last(records());
+        assert_eq!(ids(&kept), [2, 3, 4, 5]);
+
+        let kept = keep_first(&mut options(Some("a.y.1")), records())?;
+        assert_eq!(ids(&kept), [1, 2, 3, 4, 5]);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_all() -> Result<()> {
+        let mut all = Unique {
+            keep: Keep::All,
+            ..options(Some("a.x"))
+        };
+        let written = keep_first(&mut all, records())?;
+        assert_eq!(ids(&written), [1, 1, 2, 3, 4, 5]);
+        let duplicates: Vec<_> = written.iter().map(|v| v.get("_duplicate")).collect();
+        let yes = Some(&Value::Bool(true));
+        assert_eq!(duplicates, [None, yes, yes, None, None, None]);
+
+        let mut all = Unique {
+            keep: Keep::All,
+            ..options(None)
+        };
+        let err = keep_first(&mut all, vec![json!([1]), json!([1])]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cannot mark a duplicate array with `_duplicate`"
+        );
+        assert_eq!("all".parse::<Keep>()?, Keep::All);
+        Ok(())
+    }
+}
@@ -0,0 +1,385 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
+
+use json_tools::*;
+use jsonschema::JSONSchema;
+use posix_cli_utils::*;
+use serde_json::{de::IoRead, json, Value};
+
+#[derive(Debug, Clone, Parser)]
+struct ClArgs {
+    #[clap(flatten)]
+    input: InputOptions,
+    #[clap(flatten)]
+    options: ValidateArgs,
+}
+
+/// Validate a stream of JSON records against a JSON Schema.  Exits with a non-zero status if any
+/// record is invalid.
+///
+/// By default valid records are written to STDOUT, and each invalid record is reported on STDERR
+/// as a JSON object with the (zero-based) index of the record, its errors (each with the JSON
+/// pointer to the failing value and a message) and the record itself, eg.
+/// `{"record":1,"errors":[{"path":"/a","message":"..."}],"value":{"a":"1"}}`.  With --input-dir,
+/// records are counted across all the files.
+///
+/// With `--emit-valid` and/or `--emit-invalid` the records are written to those files instead,
+/// which can be used to partition a stream.  With `--validate-only` no records are written, and
+/// the errors are reported on STDOUT.
+#[derive(Debug, Clone, Args)]
+struct ValidateArgs {
+    /// JSON Schema file to validate against
+    #[clap(long)]
+    schema: PathBuf,
+    /// Don't print validation errors, only set the exit code.  No records are written either,
+    /// unless --emit-valid or --emit-invalid is given.
+    #[clap(short = 'q', long)]
+    quiet: bool,
+    /// Don't write any records, only report validation errors on STDOUT
+    #[clap(long, conflicts_with_all = &["emit-valid", "emit-invalid"])]
+    validate_only: bool,
+    /// Write valid records to FILE (`-` for STDOUT)
+    #[clap(long, value_name = "FILE")]
+    emit_valid: Option<PathBuf>,
+    /// Write invalid records to FILE (`-` for STDOUT)
+    #[clap(long, value_name = "FILE")]
+    emit_invalid: Option<PathBuf>,
+    /// Print the number of records validated so far, and how many were invalid, to STDERR every
+    /// N records
+    #[clap(long, value_name = "N")]
+    report_every: Option<NonZeroUsize>,
+}
+
+/// Where to write valid and invalid records, if anywhere.
+#[derive(Default)]
+struct Outputs<'a> {
+    valid: Option<Box<dyn Write + 'a>>,
+    invalid: Option<Box<dyn Write + 'a>>,
+}
+
+impl Outputs<'_> {
+    fn open(args: &ValidateArgs) -> Result<Self> {
+        fn open(path: &Path) -> Result<Box<dyn Write>> {
+            if path == Path::new("-") {
+                return Ok(Box::new(io::stdout()));
+            }
+            let file = File::create(path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            Ok(Box::new(BufWriter::new(file)))
+        }
+
+        let emit = args.emit_valid.is_some() || args.emit_invalid.is_some();
+        if !emit && !args.quiet && !args.validate_only {
+            return Ok(Outputs {
+                valid: Some(open(Path::new("-"))?),
+                invalid: None,
+            });
+        }
+        Ok(Outputs {
+            valid: args.emit_valid.as_deref().map(open).transpose()?,
+            invalid: args.emit_invalid.as_deref().map(open).transpose()?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.valid.is_none() && self.invalid.is_none()
+    }
+
+    fn write(&mut self, value: &Value, valid: bool) -> Result<()> {
+        let output = if valid {
+            &mut self.valid
+        } else {
+            &mut self.invalid
+        };
+        if let Some(output) = output {
+            serde_json::to_writer(&mut *output, value)?;
+            output.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        for output in [&mut self.valid, &mut self.invalid].into_iter().flatten() {
+            output.flush()?;
+        }
+        Ok(())
+    }
+}
+
+struct Validate {
+    schema: JSONSchema,
+    quiet: bool,
+    report_every: Option<NonZeroUsize>,
+    /// The number of records validated so far, and how many of them were invalid
+    count: usize,
+    invalid: usize,
+}
+
+impl Validate {
+    fn new(args: &ValidateArgs) -> Result<Self> {
+        let schema = load_json(&args.schema)?;
+        let schema = JSONSchema::compile(&schema)
+            .map_err(|e| anyhow!("invalid schema {}: {}", args.schema.display(), e))?;
+        Ok(Validate {
+            schema,
+            quiet: args.quiet,
+            report_every: args.report_every,
+            count: 0,
+            invalid: 0,
+        })
+    }
+
+    /// Count a validated record, returning a progress message every `--report-every` records.
+    fn progress(&mut self, valid: bool) -> Option<String> {
+        self.count += 1;
+        if !valid {
+            self.invalid += 1;
+        }
+        match self.report_every {
+            Some(n) if self.count.is_multiple_of(n.get()) => Some(format!(
+                "validated {} records, {} invalid so far",
+                self.count, self.invalid
+            )),
+            _ => None,
+        }
+    }
+
+    /// Validate a single record, reporting errors to `output`.  Returns `true` if the record is valid.
+    fn check(&self, index: usize, value: &Value, mut output: impl Write) -> Result<bool> {
+        if self.quiet {
+            return Ok(self.schema.is_valid(value));
+        }
+        match self.schema.validate(value) {
+            Ok(()) => Ok(true),
+            Err(errors) => {
+                let errors: Vec<_> = errors
+                    .map(|e| json!({ "path": e.instance_path.to_string(), "message": e.to_string() }))
+                    .collect();
+                let report = json!({ "record": index, "errors": errors, "value": value });
+                serde_json::to_writer(&mut output, &report)?;
+                output.write_all(b"\n")?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Validate every record in `input`, reporting errors to `report` and writing records to
+    /// `outputs`.  Returns `true` if all records are valid.
+    fn run(
+        &mut self,
+        input: impl Read,
+        mut report: impl Write,
+        outputs: &mut Outputs<'_>,
+    ) -> Result<bool> {
+        let mut all_valid = true;
+        let stream = serde_json::Deserializer::new(IoRead::new(input)).into_iter::<Value>();
+        for value in stream {
+            let value = value?;
+            let valid = self.check(self.count, &value, &mut report)?;
+            outputs.write(&value, valid)?;
+            all_valid &= valid;
+            if let Some(progress) = self.progress(valid) {
+                eprintln!("{}", progress);
+            }
+        }
+        outputs.flush()?;
+        Ok(all_valid)
+    }
+}
+
+fn main() -> Result<()> {
+    reset_sigpipe();
+
+    let ClArgs { input, options } = ClArgs::parse();
+    let mut validate = Validate::new(&options)?;
+    let mut outputs = Outputs::open(&options)?;
+    let mut report: Box<dyn Write> = if outputs.is_empty() {
+        Box::new(io::stdout())
+    } else {
+        Box::new(io::stderr())
+    };
+
+    let mut valid = true;
+    input.for_each(|input| {
+        valid &= match input {
+            Input::File(f) => validate.run(BufReader::new(f), &mut report, &mut outputs)?,
+            Input::Stdin(i) => validate.run(i.lock(), &mut report, &mut outputs)?,
+        };
+        Ok(())
+    })?;
+
+    if !valid {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(quiet: bool) -> ValidateArgs {
+        ValidateArgs {
+            schema: "tests/schema.json".into(),
+            quiet,
+            validate_only: false,
+            emit_valid: None,
+            emit_invalid: None,
+            report_every: None,
+        }
+    }
+
+    fn validate(quiet: bool) -> Validate {
+        Validate::new(&args(quiet)).unwrap()
+    }
+
+    fn run(input: &str, quiet: bool) -> (bool, String) {
+        let mut output = Vec::new();
+        let valid = validate(quiet)
+            .run(input.as_bytes(), &mut output, &mut Outputs::default())
+            .unwrap();
+        (valid, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn valid() {
+        let (valid, output) = run(r#"{"a": 1, "b": ["x"]} {"a": 2}"#, false);
+        assert!(valid);
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn invalid() -> Result<()> {
+        let (valid, output) = run(r#"{"a": 1} {"b": ["x", 2]} {"a": "1"}"#, false);
+        assert!(!valid);
+        let reports = serde_json::Deserializer::from_str(&output)
+            .into_iter::<Value>()
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0]["record"], 1);
+        assert_eq!(reports[0]["value"], json!({ "b": ["x", 2] }));
+        let paths: Vec<_> = reports[0]["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(paths, ["", "/b/1"]);
+        assert_eq!(reports[1]["record"], 2);
+        assert_eq!(reports[1]["errors"][0]["path"], "/a");
+        assert!(reports[1]["errors"][0]["message"].is_string());
+        Ok(())
+    }
+
+    #[test]
+    fn default_outputs() -> Result<()> {
+        let outputs = Outputs::open(&args(false))?;
+        assert!(outputs.valid.is_some() && outputs.invalid.is_none());
+
+        let validate_only = ValidateArgs {
+            validate_only: true,
+            ..args(false)
+        };
+        assert!(Outputs::open(&validate_only)?.is_empty());
+        assert!(Outputs::open(&args(true))?.is_empty());
+
+        let input = r#"{"a": 1} {"a": "1"} {"a": 2}"#;
+        let mut valid = Vec::new();
+        let mut report = Vec::new();
+        let mut outputs = Outputs {
+            valid: Some(Box::new(&mut valid)),
+            invalid: None,
+        };
+        assert!(!validate(false).run(input.as_bytes(), &mut report, &mut outputs)?);
+        drop(outputs);
+        assert_eq!(valid, b"{\"a\":1}\n{\"a\":2}\n");
+        let report: Value = serde_json::from_slice(&report)?;
+        assert_eq!(report["record"], 1);
+        assert_eq!(report["value"], json!({ "a": "1" }));
+        Ok(())
+    }
+
+    #[test]
+    fn index_across_inputs() -> Result<()> {
+        let mut validate = validate(false);
+        let mut report = Vec::new();
+        for input in [r#"{"a": 1} {"a": "1"}"#, r#"{"a": 2} {"a": "2"}"#] {
+            validate.run(input.as_bytes(), &mut report, &mut Outputs::default())?;
+        }
+        let records: Vec<_> = serde_json::Deserializer::from_slice(&report)
+            .into_iter::<Value>()
+            .map(|r| r.map(|r| r["record"].clone()))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(records, [1, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn quiet() {
+        let (valid, output) = run(r#"{"a": 1} {"a": "1"}"#, true);
+        assert!(!valid);
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn progress() {
+        let mut validate = Validate::new(&ValidateArgs {
+            report_every: NonZeroUsize::new(2),
+            ..args(true)
+        })
+        .unwrap();
+        let reports: Vec<_> = [true, false, true, false, false]
+            .into_iter()
+            .filter_map(|valid| validate.progress(valid))
+            .collect();
+        assert_eq!(
+            reports,
+            [
+                "validated 2 records, 1 invalid so far",
+                "validated 4 records, 2 invalid so far"
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_schema() {
+        let args = ValidateArgs {
+            schema: "tests/c.json".into(),
+            ..args(false)
+        };
+        assert!(Validate::new(&args).is_err());
+    }
+
+    #[test]
+    fn partition() {
+        let input = r#"{"a": 1} {"b": ["x", 2]} {"a": 2, "b": []} {"a": "1"}"#;
+        let mut valid = Vec::new();
+        let mut invalid = Vec::new();
+        let mut outputs = Outputs {
+            valid: Some(Box::new(&mut valid)),
+            invalid: Some(Box::new(&mut invalid)),
+        };
+        let all_valid = validate(true)
+            .run(input.as_bytes(), io::sink(), &mut outputs)
+            .unwrap();
+        drop(outputs);
+        assert!(!all_valid);
+        assert_eq!(valid, b"{\"a\":1}\n{\"a\":2,\"b\":[]}\n");
+        assert_eq!(invalid, b"{\"b\":[\"x\",2]}\n{\"a\":\"1\"}\n");
+
+        let mut invalid = Vec::new();
+        let mut outputs = Outputs {
+            valid: None,
+            invalid: Some(Box::new(&mut invalid)),
+        };
+        validate(true)
+            .run(input.as_bytes(), io::sink(), &mut outputs)
+            .unwrap();
+        drop(outputs);
+        assert_eq!(invalid.iter().filter(|&&b| b == b'\n').count(), 2);
+    }
+}
@@ -1,47 +1,1049 @@
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use posix_cli_utils::*;
-use serde::Serializer;
-use serde_json::{de::IoRead, Deserializer, Value};
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
+use regex::Regex;
+use serde::{Serialize, Serializer};
+use serde_json::{
+    de::IoRead,
+    ser::{CompactFormatter, Formatter, PrettyFormatter},
+    Deserializer, Value,
+};
+use std::cmp::Ordering;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 
+/// A tool which transforms each JSON value in a stream independently.
+///
+/// `process_one` takes `&mut self`, so implementations can keep scratch buffers (eg. maps or
+/// strings which are cleared and reused) between records rather than allocating for each one.
 pub trait RunStreamJson: Sized {
-    fn process_one<S>(&mut self, value: Value, output: S) -> Result<()>
+    /// Process a single record, serializing the result to `output`.  Return `None` to skip
+    /// the record without writing anything.
+    fn process_one<S>(&mut self, value: Value, output: S) -> Result<Option<S::Ok>>
     where
         S: Serializer,
         S::Error: Send + Sync + 'static;
 
-    fn main<R: Read>(&mut self, input: Input<R>) -> Result<()> {
-        match input {
-            Input::File(file) => run_json_stream_impl(file, self),
-            Input::Stdin(input) => run_json_stream_impl(input, self),
+    /// Split a record into the records passed to `process_one`, eg. one per element of an array.
+    /// By default each record is processed as it is.
+    fn split_record(&mut self, value: Value) -> Result<Vec<Value>> {
+        Ok(vec![value])
+    }
+
+    /// Called once after the last record, to write anything held back until the end of the
+    /// stream.  By default nothing is written.
+    fn finish<S>(&mut self, _output: S) -> Result<Option<S::Ok>>
+    where
+        S: Serializer,
+        S::Error: Send + Sync + 'static,
+    {
+        Ok(None)
+    }
+
+    fn main(&mut self, input: &InputOptions, output: &OutputOptions) -> Result<()> {
+        let count = output.with_output(|w| {
+            let mut count = 0;
+            let mut throttle = Throttle::new(output.rate_limit);
+            input.for_each(|input| {
+                count += run_json_stream_input(input, &mut *w, self, output, &mut throttle)?;
+                Ok(())
+            })?;
+            let mut buf = Vec::new();
+            if output
+                .write(&mut buf, Finish(self))
+                .context("failed to finish output")?
+            {
+                buf.push(b'\n');
+                throttle.write_record(&mut *w, &buf)?;
+            }
+            Ok(count)
+        })?;
+        if output.count {
+            eprintln!("{}", count);
+        }
+        Ok(())
+    }
+}
+
+fn run_json_stream_input<R, W, T>(
+    input: Input<R>,
+    writer: W,
+    run: &mut T,
+    output: &OutputOptions,
+    throttle: &mut Throttle,
+) -> Result<usize>
+where
+    T: RunStreamJson,
+    R: Read,
+    W: Write,
+{
+    match input {
+        Input::File(file) => {
+            run_json_stream_impl(BufReader::new(file), writer, run, output, throttle)
         }
+        Input::Stdin(input) => run_json_stream_impl(input.lock(), writer, run, output, throttle),
     }
 }
 
-fn run_json_stream_impl<R, T>(input: R, run: &mut T) -> Result<()>
+/// Returns the number of records read.
+fn run_json_stream_impl<R, W, T>(
+    input: R,
+    mut writer: W,
+    run: &mut T,
+    output: &OutputOptions,
+    throttle: &mut Throttle,
+) -> Result<usize>
 where
     T: RunStreamJson,
     R: Read,
+    W: Write,
 {
     let stream = Deserializer::new(IoRead::new(input)).into_iter::<Value>();
-    let mut stdout = std::io::stdout();
+    let mut buf = Vec::new();
+    let mut count = 0;
 
     for value in stream {
-        let mut output = serde_json::Serializer::new(stdout.lock());
-        run.process_one(value?, &mut output)?;
-        drop(output);
-        stdout.write_all(b"\n")?;
+        let context = || format!("failed to process record {}", count);
+        for value in run.split_record(value?).with_context(context)? {
+            buf.clear();
+            let written = output
+                .write(&mut buf, ProcessOne(run, value))
+                .with_context(context)?;
+            if written {
+                buf.push(b'\n');
+                throttle.write_record(&mut writer, &buf)?;
+            }
+        }
+        count += 1;
     }
+    Ok(count)
+}
+
+/// Apply `f` to each JSON value in `input`, writing each value it returns to STDOUT.
+/// Values for which `f` returns `None` are dropped.
+///
+/// A tool which uppercases every string field:
+/// ```no_run
+/// use json_tools::run_json_stream;
+/// use posix_cli_utils::*;
+/// use serde_json::Value;
+///
+/// fn main() -> Result<()> {
+///     run_json_stream(Input::default_stdin(None::<&str>)?, |mut value| {
+///         for v in value.as_object_mut().into_iter().flat_map(|m| m.values_mut()) {
+///             if let Value::String(s) = v { *s = s.to_uppercase(); }
+///         }
+///         Ok(Some(value))
+///     })
+/// }
+/// ```
+pub fn run_json_stream<R, F>(input: Input<R>, f: F) -> Result<()>
+where
+    R: Read,
+    F: FnMut(Value) -> Result<Option<Value>>,
+{
+    let output = OutputOptions::default();
+    output.with_output(|w| {
+        let mut throttle = Throttle::new(None);
+        run_json_stream_input(input, w, &mut StreamFn(f), &output, &mut throttle)
+    })?;
     Ok(())
 }
 
+struct StreamFn<F>(F);
+
+/// Spaces out the records written with `--rate-limit`.
+struct Throttle {
+    /// The minimum time between records, or `None` if records aren't throttled
+    interval: Option<Duration>,
+    /// The earliest time the next record can be written
+    next: Option<Instant>,
+}
+
+impl Throttle {
+    fn new(rate_limit: Option<RateLimit>) -> Self {
+        Throttle {
+            interval: rate_limit.map(|RateLimit(n)| Duration::from_secs_f64(1.0 / n)),
+            next: None,
+        }
+    }
+
+    /// Write a single record, first sleeping until it can be written.  Throttled records are
+    /// flushed straight away, so that they are seen at the rate they are written.
+    fn write_record(&mut self, mut writer: impl Write, record: &[u8]) -> Result<()> {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => {
+                writer.write_all(record)?;
+                return Ok(());
+            }
+        };
+        let now = Instant::now();
+        let at = match self.next {
+            Some(next) if next > now => {
+                thread::sleep(next - now);
+                next
+            }
+            _ => now,
+        };
+        self.next = Some(at + interval);
+        writer.write_all(record)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<F> RunStreamJson for StreamFn<F>
+where
+    F: FnMut(Value) -> Result<Option<Value>>,
+{
+    fn process_one<S>(&mut self, value: Value, output: S) -> Result<Option<S::Ok>>
+    where
+        S: Serializer,
+        S::Error: Send + Sync + 'static,
+    {
+        match (self.0)(value)? {
+            Some(value) => Ok(Some(value.serialize(output)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Something which can be written out by any `Serializer`.
+trait SerializeWith {
+    fn serialize_with<S>(self, serializer: S) -> Result<Option<S::Ok>>
+    where
+        S: Serializer,
+        S::Error: Send + Sync + 'static;
+}
+
+struct ProcessOne<'a, T>(&'a mut T, Value);
+
+impl<T: RunStreamJson> SerializeWith for ProcessOne<'_, T> {
+    fn serialize_with<S>(self, serializer: S) -> Result<Option<S::Ok>>
+    where
+        S: Serializer,
+        S::Error: Send + Sync + 'static,
+    {
+        self.0.process_one(self.1, serializer)
+    }
+}
+
+struct Finish<'a, T>(&'a mut T);
+
+impl<T: RunStreamJson> SerializeWith for Finish<'_, T> {
+    fn serialize_with<S>(self, serializer: S) -> Result<Option<S::Ok>>
+    where
+        S: Serializer,
+        S::Error: Send + Sync + 'static,
+    {
+        self.0.finish(serializer)
+    }
+}
+
+struct SerializeValue<'a, T>(&'a T);
+
+impl<T: Serialize> SerializeWith for SerializeValue<'_, T> {
+    fn serialize_with<S>(self, serializer: S) -> Result<Option<S::Ok>>
+    where
+        S: Serializer,
+        S::Error: Send + Sync + 'static,
+    {
+        Ok(Some(self.0.serialize(serializer)?))
+    }
+}
+
+/// A compression format for input or output streams
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    /// Guess the compression format from a file name extension, `.gz` or `.zst`.
+    fn from_path(path: &Path) -> Option<Codec> {
+        match path.extension()?.to_str()? {
+            "gz" => Some(Codec::Gzip),
+            "zst" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    fn reader<'a>(self, reader: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Codec::Gzip => Box::new(GzDecoder::new(reader)),
+            Codec::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        })
+    }
+
+    fn writer<'a>(self, writer: impl Write + 'a) -> Result<Box<dyn FinishWrite + 'a>> {
+        Ok(match self {
+            Codec::Gzip => Box::new(GzEncoder::new(writer, Compression::default())),
+            Codec::Zstd => Box::new(zstd::Encoder::new(writer, 0)?),
+        })
+    }
+}
+
+/// A writer which has to be finished once everything is written, eg. to write a compression trailer.
+trait FinishWrite: Write {
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+impl<W: Write> FinishWrite for GzEncoder<W> {
+    fn finish(self: Box<Self>) -> Result<()> {
+        GzEncoder::finish(*self)?.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write> FinishWrite for zstd::Encoder<'_, W> {
+    fn finish(self: Box<Self>) -> Result<()> {
+        zstd::Encoder::finish(*self)?.flush()?;
+        Ok(())
+    }
+}
+
+impl FinishWrite for Output {
+    fn finish(self: Box<Self>) -> Result<()> {
+        self.commit()
+    }
+}
+
+// Where to read a stream of JSON values from.
+//
+// Compressed input is detected from the file name extension (`.gz` or `.zst`).  `--gzip-in` and
+// `--zstd-in` take precedence over the extension, so `--gzip-in data.zst` is read as gzip.
+// With `--input-dir`, each file is read as a separate stream, one after another.
+//
+// (Not a doc comment, since clap would use it as the about text of every tool.)
+#[derive(Debug, Clone, Default, Args)]
+pub struct InputOptions {
+    /// Input JSON file (defaults to STDIN)
+    #[clap(value_name = "INPUT")]
+    pub path: Option<PathBuf>,
+    /// Decompress gzipped input, regardless of the file name
+    #[clap(long, conflicts_with = "zstd-in")]
+    pub gzip_in: bool,
+    /// Decompress zstd-compressed input, regardless of the file name
+    #[clap(long)]
+    pub zstd_in: bool,
+    /// Read every file in DIR, in order of file name, instead of INPUT
+    #[clap(long, value_name = "DIR", conflicts_with = "path")]
+    pub input_dir: Option<PathBuf>,
+    /// With --input-dir, only read files whose name matches PATTERN, where `*` matches any number
+    /// of characters and `?` matches one character
+    #[clap(long, value_name = "PATTERN", requires = "input-dir")]
+    pub glob: Option<String>,
+}
+
+/// Convert a file name pattern to a regex, see `InputOptions::glob`.
+fn glob_regex(pattern: &str) -> Result<Regex> {
+    let mut re = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re.push('$');
+    Ok(Regex::new(&re)?)
+}
+
+impl InputOptions {
+    fn codec(&self, path: Option<&Path>) -> Option<Codec> {
+        if self.gzip_in {
+            Some(Codec::Gzip)
+        } else if self.zstd_in {
+            Some(Codec::Zstd)
+        } else {
+            path.and_then(Codec::from_path)
+        }
+    }
+
+    /// Call `f` with each input in turn: INPUT (or STDIN), or every file in `--input-dir`.  Errors
+    /// from files in `--input-dir` say which file they came from.
+    pub fn for_each(&self, mut f: impl FnMut(Input<Box<dyn Read>>) -> Result<()>) -> Result<()> {
+        let dir = match &self.input_dir {
+            Some(dir) => dir,
+            None => return f(self.open()?),
+        };
+        for path in self.dir_files(dir)? {
+            let file =
+                File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+            let input = match self.codec(Some(&path)) {
+                Some(codec) => codec.reader(file)?,
+                None => Box::new(file),
+            };
+            f(Input::File(input)).with_context(|| format!("error in {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// The files in `dir` matching `--glob`, sorted by name.
+    fn dir_files(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let pattern = self.glob.as_deref().map(glob_regex).transpose()?;
+        let mut files = Vec::new();
+        let entries =
+            fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let matches = match &pattern {
+                Some(pattern) => pattern.is_match(&name),
+                None => true,
+            };
+            if matches && path.is_file() {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Open INPUT (or STDIN), decompressing it if necessary.
+    fn open(&self) -> Result<Input<Box<dyn Read>>> {
+        let codec = self.codec(self.path.as_deref());
+        let input = match (Input::default_stdin(self.path.as_ref())?, codec) {
+            (Input::File(f), Some(codec)) => Input::File(codec.reader(f)?),
+            (Input::File(f), None) => Input::File(Box::new(f) as Box<dyn Read>),
+            (Input::Stdin(i), Some(codec)) => Input::File(codec.reader(i)?),
+            (Input::Stdin(i), None) => Input::Stdin(i),
+        };
+        Ok(input)
+    }
+}
+
+/// Indentation used when pretty-printing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indent {
+    Spaces(usize),
+    Tab,
+}
+
+impl FromStr for Indent {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tab" => Ok(Indent::Tab),
+            n => n
+                .parse()
+                .map(Indent::Spaces)
+                .map_err(|_| anyhow!("expected a number of spaces or `tab`: {}", s)),
+        }
+    }
+}
+
+impl Indent {
+    fn bytes(self) -> Vec<u8> {
+        match self {
+            Indent::Spaces(n) => vec![b' '; n],
+            Indent::Tab => vec![b'\t'],
+        }
+    }
+}
+
+/// The number of records per second allowed by `--rate-limit`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit(pub f64);
+
+impl FromStr for RateLimit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.parse::<f64>() {
+            Ok(n) if n > 0.0 && n.is_finite() => Ok(RateLimit(n)),
+            _ => bail!("expected a positive number of records per second: {}", s),
+        }
+    }
+}
+
+/// The order used by `--sort-keys`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKeys {
+    /// Compare keys as strings
+    Lexical,
+    /// Compare runs of digits in keys by their numeric value, see `natural_cmp`
+    Natural,
+}
+
+impl FromStr for SortKeys {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lexical" => Ok(SortKeys::Lexical),
+            "natural" => Ok(SortKeys::Natural),
+            _ => bail!("expected `lexical` or `natural`: {}", s),
+        }
+    }
+}
+
+/// Split `s` after its leading ASCII digits.
+fn split_digits(s: &str) -> (&str, &str) {
+    s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()))
+}
+
+/// Compare strings so that runs of digits are compared by their numeric value, eg. `a.2` comes
+/// before `a.10`.  Numbers which are equal except for leading zeros are ordered by length.
+pub fn natural_cmp(mut a: &str, mut b: &str) -> Ordering {
+    loop {
+        let (x, y) = match (a.chars().next(), b.chars().next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => (x, y),
+        };
+        if x.is_ascii_digit() && y.is_ascii_digit() {
+            let ((m, rest_a), (n, rest_b)) = (split_digits(a), split_digits(b));
+            let (m_trimmed, n_trimmed) = (m.trim_start_matches('0'), n.trim_start_matches('0'));
+            let ord = m_trimmed
+                .len()
+                .cmp(&n_trimmed.len())
+                .then_with(|| m_trimmed.cmp(n_trimmed))
+                .then_with(|| m.len().cmp(&n.len()));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            a = rest_a;
+            b = rest_b;
+        } else if x != y {
+            return x.cmp(&y);
+        } else {
+            a = &a[x.len_utf8()..];
+            b = &b[y.len_utf8()..];
+        }
+    }
+}
+
+/// A case style for object keys, see `KeyCase::apply`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCase {
+    /// `user_id`
+    Snake,
+    /// `userId`
+    Camel,
+    /// `user-id`
+    Kebab,
+    /// `userid`
+    Lower,
+    /// `USERID`
+    Upper,
+}
+
+impl FromStr for KeyCase {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "snake" => Ok(KeyCase::Snake),
+            "camel" => Ok(KeyCase::Camel),
+            "kebab" => Ok(KeyCase::Kebab),
+            "lower" => Ok(KeyCase::Lower),
+            "upper" => Ok(KeyCase::Upper),
+            _ => bail!(
+                "expected `snake`, `camel`, `kebab`, `lower` or `upper`: {}",
+                s
+            ),
+        }
+    }
+}
+
+/// Split `s` into words at `_`, `-` and whitespace, and where the case changes, eg. `userID` and
+/// `user_id` are both `["user", "ID"]` and `HTTPServer` is `["HTTP", "Server"]`.
+fn words(s: &str) -> Vec<&str> {
+    let chars: Vec<_> = s.char_indices().collect();
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, &(pos, c)) in chars.iter().enumerate() {
+        if c == '_' || c == '-' || c.is_whitespace() {
+            if let Some(start) = start.take() {
+                words.push(&s[start..pos]);
+            }
+            continue;
+        }
+        match start {
+            Some(word) if c.is_uppercase() => {
+                let prev = chars[i - 1].1;
+                let next_lower = matches!(chars.get(i + 1), Some(&(_, n)) if n.is_lowercase());
+                if !prev.is_uppercase() || next_lower {
+                    words.push(&s[word..pos]);
+                    start = Some(pos);
+                }
+            }
+            Some(_) => {}
+            None => start = Some(pos),
+        }
+    }
+    words.extend(start.map(|start| &s[start..]));
+    words
+}
+
+impl KeyCase {
+    /// Convert `key` to this case.  Leading and trailing underscores are kept, so `_id` stays
+    /// `_id` in every case but `upper`.
+    pub fn apply(self, key: &str) -> String {
+        match self {
+            KeyCase::Lower => return key.to_lowercase(),
+            KeyCase::Upper => return key.to_uppercase(),
+            _ => {}
+        }
+        let inner = key.trim_matches('_');
+        let start = key.len() - key.trim_start_matches('_').len();
+        let mut converted = key[..start].to_string();
+        for (i, word) in words(inner).into_iter().enumerate() {
+            match self {
+                KeyCase::Snake if i > 0 => converted.push('_'),
+                KeyCase::Kebab if i > 0 => converted.push('-'),
+                KeyCase::Camel if i > 0 => {
+                    let mut chars = word.chars();
+                    converted.extend(chars.next().into_iter().flat_map(char::to_uppercase));
+                    converted.push_str(&chars.as_str().to_lowercase());
+                    continue;
+                }
+                _ => {}
+            }
+            converted.push_str(&word.to_lowercase());
+        }
+        converted.push_str(&key[start + inner.len()..]);
+        converted
+    }
+}
+
+/// Write `value` to `buf` as compact JSON with object keys sorted and numbers which are whole
+/// written as integers, so that values which only differ in key order or in how a number is
+/// written (eg. `1` and `1.0`) are written the same.  Useful as a hashable key for a value.
+pub fn write_canonical(buf: &mut String, value: &Value) {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by_key(|&(k, _)| k);
+            buf.push('{');
+            for (i, (k, v)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    buf.push(',');
+                }
+                write!(buf, "{}:", Value::from(k.as_str())).unwrap();
+                write_canonical(buf, v);
+            }
+            buf.push('}');
+        }
+        Value::Array(items) => {
+            buf.push('[');
+            for (i, v) in items.iter().enumerate() {
+                if i > 0 {
+                    buf.push(',');
+                }
+                write_canonical(buf, v);
+            }
+            buf.push(']');
+        }
+        Value::Number(n) if n.is_f64() => match n.as_f64() {
+            Some(x) if x.fract() == 0.0 && x.abs() < (1u64 << 53) as f64 => {
+                write!(buf, "{}", x as i64).unwrap()
+            }
+            _ => write!(buf, "{}", n).unwrap(),
+        },
+        scalar => write!(buf, "{}", scalar).unwrap(),
+    }
+}
+
+/// Where output is written: a file, STDOUT, or a file being replaced.
+pub enum Output {
+    File(io::BufWriter<File>),
+    Stdout(io::StdoutLock<'static>),
+    InPlace(InPlace),
+}
+
+/// A temporary file which replaces `dest` once everything is written, see `Output::in_place`.
+pub struct InPlace {
+    file: io::BufWriter<File>,
+    tmp: PathBuf,
+    dest: PathBuf,
+    done: bool,
+}
+
+impl Drop for InPlace {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+impl Output {
+    /// Create (or truncate) the file at `path`, or use STDOUT if there is no path.
+    pub fn default_stdout(path: Option<impl AsRef<Path>>) -> Result<Self> {
+        match path {
+            Some(path) => {
+                let path = path.as_ref();
+                let file = File::create(path)
+                    .with_context(|| format!("failed to create {}", path.display()))?;
+                Ok(Output::File(io::BufWriter::new(file)))
+            }
+            None => Ok(Output::Stdout(io::stdout().lock())),
+        }
+    }
+
+    /// Write to a temporary file next to `path`, which replaces `path` when the output is
+    /// committed.  If the output is dropped without being committed, the temporary file is removed.
+    pub fn in_place(path: impl AsRef<Path>) -> Result<Self> {
+        let dest = path.as_ref().to_path_buf();
+        let name = dest
+            .file_name()
+            .ok_or_else(|| anyhow!("cannot edit {} in place", dest.display()))?;
+        let tmp = dest.with_file_name(format!(
+            ".{}.{}.tmp",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        let file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&tmp)
+            .with_context(|| format!("failed to create {}", tmp.display()))?;
+        let in_place = InPlace {
+            file: io::BufWriter::new(file),
+            tmp,
+            dest,
+            done: false,
+        };
+        if let Ok(metadata) = fs::metadata(&in_place.dest) {
+            fs::set_permissions(&in_place.tmp, metadata.permissions())?;
+        }
+        Ok(Output::InPlace(in_place))
+    }
+
+    /// Flush the output.  For in-place output, this replaces the original file.
+    pub fn commit(mut self) -> Result<()> {
+        self.flush()?;
+        if let Output::InPlace(f) = &mut self {
+            fs::rename(&f.tmp, &f.dest)
+                .with_context(|| format!("failed to replace {}", f.dest.display()))?;
+            f.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::File(f) => f.write(buf),
+            Output::Stdout(s) => s.write(buf),
+            Output::InPlace(f) => f.file.write(buf),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Output::File(f) => f.write_all(buf),
+            Output::Stdout(s) => s.write_all(buf),
+            Output::InPlace(f) => f.file.write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::File(f) => f.flush(),
+            Output::Stdout(s) => s.flush(),
+            Output::InPlace(f) => f.file.flush(),
+        }
+    }
+}
+
+/// A file written alongside the main output and compressed the same way, see
+/// `OutputOptions::create_file`.
+pub struct OutputFile(Box<dyn FinishWrite>);
+
+impl OutputFile {
+    /// Finish compressing (if necessary) and flush the file.
+    pub fn finish(self) -> Result<()> {
+        self.0.finish()
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// Options controlling how JSON output is formatted and reported.
+#[derive(Debug, Clone, Default, Args)]
+pub struct OutputOptions {
+    /// Write output to FILE instead of STDOUT
+    #[clap(short = 'o', long = "output", value_name = "FILE")]
+    pub output_file: Option<PathBuf>,
+    /// Replace this file with the output instead, for tools with an `--in-place` option
+    #[clap(skip)]
+    pub in_place: Option<PathBuf>,
+    /// Pretty-print output
+    #[clap(long)]
+    pub pretty: bool,
+    /// Number of spaces to indent by when pretty-printing, or `tab` to indent with tabs (implies
+    /// --pretty)
+    #[clap(long, value_name = "N")]
+    pub indent: Option<Indent>,
+    /// Serialize object keys in sorted order.  With `--sort-keys=natural`, runs of digits in keys
+    /// are compared by their numeric value, so `a.2` comes before `a.10`.
+    #[clap(
+        long,
+        value_name = "ORDER",
+        min_values = 0,
+        max_values = 1,
+        require_equals = true,
+        default_missing_value = "lexical"
+    )]
+    pub sort_keys: Option<SortKeys>,
+    /// Escape non-ASCII characters in strings
+    #[clap(long)]
+    pub ascii: bool,
+    /// Print the number of input records processed to STDERR when finished
+    #[clap(long)]
+    pub count: bool,
+    /// Compress output with gzip
+    #[clap(long, conflicts_with = "zstd-out")]
+    pub gzip_out: bool,
+    /// Compress output with zstd
+    #[clap(long)]
+    pub zstd_out: bool,
+    /// Write at most N records per second, sleeping between records as needed, eg. when the
+    /// output feeds a rate-limited API.  N can be fractional, so `0.5` writes a record every two
+    /// seconds.  Only applies to tools which write each record as soon as it's processed.
+    #[clap(long, value_name = "N")]
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl OutputOptions {
+    fn codec(&self) -> Option<Codec> {
+        if self.gzip_out {
+            Some(Codec::Gzip)
+        } else if self.zstd_out {
+            Some(Codec::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Run `f` with the output (`-o FILE`, the file edited in place, or STDOUT), compressed if
+    /// requested.  A file edited in place is only replaced if `f` succeeds.
+    pub fn with_output<T>(&self, f: impl FnOnce(&mut dyn Write) -> Result<T>) -> Result<T> {
+        let mut output = match &self.in_place {
+            Some(path) => Output::in_place(path)?,
+            None => Output::default_stdout(self.output_file.as_ref())?,
+        };
+        let result = match self.codec() {
+            Some(codec) => {
+                let mut writer = codec.writer(&mut output)?;
+                let result = f(&mut writer)?;
+                writer.finish()?;
+                result
+            }
+            None => f(&mut output)?,
+        };
+        output.commit()?;
+        Ok(result)
+    }
+
+    /// Create (or truncate) the file at `path` as a second output, compressed like the main
+    /// output with --gzip-out or --zstd-out.
+    pub fn create_file(&self, path: impl AsRef<Path>) -> Result<OutputFile> {
+        let output = Output::default_stdout(Some(path))?;
+        match self.codec() {
+            Some(codec) => Ok(OutputFile(codec.writer(output)?)),
+            None => Ok(OutputFile(Box::new(output))),
+        }
+    }
+
+    pub fn serialize_to<W: Write>(&self, w: W, value: &impl Serialize) -> Result<()> {
+        self.write(w, SerializeValue(value))?;
+        Ok(())
+    }
+
+    /// Returns `false` if nothing was written.
+    fn write<W: Write>(&self, w: W, value: impl SerializeWith) -> Result<bool> {
+        if let Some(order) = self.sort_keys {
+            match value.serialize_with(serde_json::value::Serializer)? {
+                Some(mut value) => {
+                    sort_keys(&mut value, order);
+                    self.write_formatted(w, SerializeValue(&value))
+                }
+                None => Ok(false),
+            }
+        } else {
+            self.write_formatted(w, value)
+        }
+    }
+
+    fn write_formatted<W: Write>(&self, w: W, value: impl SerializeWith) -> Result<bool> {
+        let indent = match (self.pretty, self.indent) {
+            (_, Some(indent)) => Some(indent.bytes()),
+            (true, None) => Some(Indent::Spaces(2).bytes()),
+            (false, None) => None,
+        };
+        match (indent, self.ascii) {
+            (None, false) => write_with(w, CompactFormatter, value),
+            (None, true) => write_with(w, Ascii(CompactFormatter), value),
+            (Some(indent), false) => write_with(w, PrettyFormatter::with_indent(&indent), value),
+            (Some(indent), true) => {
+                write_with(w, Ascii(PrettyFormatter::with_indent(&indent)), value)
+            }
+        }
+    }
+}
+
+fn write_with<W, F, T>(w: W, formatter: F, value: T) -> Result<bool>
+where
+    W: Write,
+    F: Formatter,
+    T: SerializeWith,
+{
+    let written =
+        value.serialize_with(&mut serde_json::Serializer::with_formatter(w, formatter))?;
+    Ok(written.is_some())
+}
+
+fn sort_keys(value: &mut Value, order: SortKeys) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(|v| sort_keys(v, order)),
+        Value::Object(map) => {
+            let mut items: Vec<_> = std::mem::take(map).into_iter().collect();
+            match order {
+                SortKeys::Lexical => items.sort_by(|(a, _), (b, _)| a.cmp(b)),
+                SortKeys::Natural => items.sort_by(|(a, _), (b, _)| natural_cmp(a, b)),
+            }
+            for (k, mut v) in items {
+                sort_keys(&mut v, order);
+                map.insert(k, v);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Wraps a `Formatter`, escaping all non-ASCII characters in strings.
+struct Ascii<F>(F);
+
+impl<F: Formatter> Formatter for Ascii<F> {
+    fn write_string_fragment<W>(&mut self, writer: &mut W, fragment: &str) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        let mut start = 0;
+        for (i, c) in fragment.char_indices() {
+            if c.is_ascii() {
+                continue;
+            }
+            writer.write_all(&fragment.as_bytes()[start..i])?;
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                write!(writer, "\\u{:04x}", unit)?;
+            }
+            start = i + c.len_utf8();
+        }
+        writer.write_all(&fragment.as_bytes()[start..])
+    }
+
+    fn begin_array<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.begin_array(writer)
+    }
+
+    fn end_array<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.end_array(writer)
+    }
+
+    fn begin_array_value<W: ?Sized + Write>(&mut self, w: &mut W, first: bool) -> io::Result<()> {
+        self.0.begin_array_value(w, first)
+    }
+
+    fn end_array_value<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.end_array_value(writer)
+    }
+
+    fn begin_object<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.begin_object(writer)
+    }
+
+    fn end_object<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.end_object(writer)
+    }
+
+    fn begin_object_key<W: ?Sized + Write>(&mut self, w: &mut W, first: bool) -> io::Result<()> {
+        self.0.begin_object_key(w, first)
+    }
+
+    fn end_object_key<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.end_object_key(writer)
+    }
+
+    fn begin_object_value<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.begin_object_value(writer)
+    }
+
+    fn end_object_value<W: ?Sized + Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.0.end_object_value(writer)
+    }
+}
+
 pub fn load_json(path: impl AsRef<Path>) -> Result<Value> {
     let path = path.as_ref();
     let file = File::open(path).with_context(|| format!("failed to read {}", path.display()))?;
     serde_json::from_reader(file).with_context(|| format!("failed to parse {}", path.display()))
 }
 
+/// An error from one of the `ValueExt::expect_*` methods
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonToolsError {
+    /// The value isn't of the expected JSON type
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// A number doesn't fit in the requested integer type
+    NumberConversion {
+        target: &'static str,
+        number: serde_json::Number,
+    },
+}
+
+impl std::fmt::Display for JsonToolsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JsonToolsError::TypeMismatch { expected, found } => {
+                write!(f, "expected JSON {}, not {}", expected, found)
+            }
+            JsonToolsError::NumberConversion { target, number } => {
+                write!(f, "cannot convert to {}: {}", target, number)
+            }
+        }
+    }
+}
+
+impl std::error::Error for JsonToolsError {}
+
+impl JsonToolsError {
+    fn mismatch(expected: &'static str, found: &Value) -> Self {
+        JsonToolsError::TypeMismatch {
+            expected,
+            found: found.type_name(),
+        }
+    }
+}
+
 pub trait ValueExt {
     fn type_name(&self) -> &'static str;
     fn unwrap_array(self) -> Vec<Value>;
@@ -49,12 +1051,42 @@ pub trait ValueExt {
     fn unwrap_string(self) -> String;
     fn unwrap_str(&self) -> &str;
 
-    fn expect_string(self) -> Result<String>;
-    fn expect_object(self) -> Result<serde_json::Map<String, Value>>;
-    fn expect_array(self) -> Result<Vec<Value>>;
-    fn expect_number(self) -> Result<serde_json::Number>;
-    fn expect_int(self) -> Result<i64>;
-    fn expect_uint(self) -> Result<u64>;
+    fn expect_string(self) -> Result<String, JsonToolsError>;
+    fn expect_object(self) -> Result<serde_json::Map<String, Value>, JsonToolsError>;
+    fn expect_array(self) -> Result<Vec<Value>, JsonToolsError>;
+    fn expect_number(self) -> Result<serde_json::Number, JsonToolsError>;
+    fn expect_int(self) -> Result<i64, JsonToolsError>;
+    fn expect_uint(self) -> Result<u64, JsonToolsError>;
+
+    /// Borrowing versions of `expect_string`, `expect_array` and `expect_object`, for checking
+    /// the type of a value without giving it up.
+    fn expect_str(&self) -> Result<&str, JsonToolsError>;
+    fn expect_arr(&self) -> Result<&Vec<Value>, JsonToolsError>;
+    fn expect_obj(&self) -> Result<&serde_json::Map<String, Value>, JsonToolsError>;
+    /// The value of a number as a float, which may lose precision.
+    fn expect_f64(&self) -> Result<f64, JsonToolsError>;
+
+    /// Follow a path of object keys and array indices, eg `["a", "0", "b"]` for `a.0.b`.
+    fn get_path<'a>(&self, path: impl IntoIterator<Item = &'a str>) -> Option<&Value>;
+    fn get_path_mut<'a>(&mut self, path: impl IntoIterator<Item = &'a str>) -> Option<&mut Value>;
+
+    /// Render an object as a table of keys and values, or an array as a numbered list, with
+    /// box-drawing borders.  Nested values are shown as compact JSON, strings without quotes.
+    /// Anything else (including empty objects and arrays) is rendered as compact JSON.
+    fn as_table_string(&self) -> String;
+
+    /// Deep merge `other` into this value.  Where both are objects, their keys are merged
+    /// recursively and new keys are added at the end.  Otherwise (including arrays and `null`)
+    /// `other` replaces this value.
+    fn merge(&mut self, other: Value);
+}
+
+/// A cell of `ValueExt::as_table_string`.
+fn table_cell(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.escape_debug().to_string(),
+        other => other.to_string(),
+    }
 }
 
 impl ValueExt for Value {
@@ -106,43 +1138,604 @@ impl ValueExt for Value {
         }
     }
 
-    fn expect_string(self) -> Result<String> {
+    fn expect_string(self) -> Result<String, JsonToolsError> {
         match self {
             Value::String(s) => Ok(s),
-            other => bail!("expected JSON string, not {}", other.type_name()),
+            other => Err(JsonToolsError::mismatch("string", &other)),
         }
     }
 
-    fn expect_object(self) -> Result<serde_json::Map<String, Value>> {
+    fn expect_object(self) -> Result<serde_json::Map<String, Value>, JsonToolsError> {
         match self {
             Value::Object(v) => Ok(v),
-            other => bail!("expected JSON object, not {}", other.type_name()),
+            other => Err(JsonToolsError::mismatch("object", &other)),
         }
     }
 
-    fn expect_array(self) -> Result<Vec<Value>> {
+    fn expect_array(self) -> Result<Vec<Value>, JsonToolsError> {
         match self {
             Value::Array(v) => Ok(v),
-            other => bail!("expected JSON array, not {}", other.type_name()),
+            other => Err(JsonToolsError::mismatch("array", &other)),
         }
     }
 
-    fn expect_number(self) -> Result<serde_json::Number> {
+    fn expect_number(self) -> Result<serde_json::Number, JsonToolsError> {
         match self {
             Value::Number(v) => Ok(v),
-            other => bail!("expected JSON number, not {}", other.type_name()),
+            other => Err(JsonToolsError::mismatch("number", &other)),
         }
     }
 
-    fn expect_int(self) -> Result<i64> {
+    fn expect_int(self) -> Result<i64, JsonToolsError> {
         let n = self.expect_number()?;
-        n.as_i64()
-            .ok_or_else(|| anyhow!("cannot convert to integer: {}", n))
+        n.as_i64().ok_or(JsonToolsError::NumberConversion {
+            target: "integer",
+            number: n,
+        })
     }
 
-    fn expect_uint(self) -> Result<u64> {
+    fn expect_uint(self) -> Result<u64, JsonToolsError> {
         let n = self.expect_number()?;
-        n.as_u64()
-            .ok_or_else(|| anyhow!("cannot convert to unsigned integer: {}", n))
+        n.as_u64().ok_or(JsonToolsError::NumberConversion {
+            target: "unsigned integer",
+            number: n,
+        })
+    }
+
+    fn expect_str(&self) -> Result<&str, JsonToolsError> {
+        match self {
+            Value::String(s) => Ok(s),
+            other => Err(JsonToolsError::mismatch("string", other)),
+        }
+    }
+
+    fn expect_arr(&self) -> Result<&Vec<Value>, JsonToolsError> {
+        match self {
+            Value::Array(v) => Ok(v),
+            other => Err(JsonToolsError::mismatch("array", other)),
+        }
+    }
+
+    fn expect_obj(&self) -> Result<&serde_json::Map<String, Value>, JsonToolsError> {
+        match self {
+            Value::Object(v) => Ok(v),
+            other => Err(JsonToolsError::mismatch("object", other)),
+        }
+    }
+
+    fn expect_f64(&self) -> Result<f64, JsonToolsError> {
+        match self {
+            Value::Number(n) => n.as_f64().ok_or_else(|| JsonToolsError::NumberConversion {
+                target: "float",
+                number: n.clone(),
+            }),
+            other => Err(JsonToolsError::mismatch("number", other)),
+        }
+    }
+
+    fn get_path<'a>(&self, path: impl IntoIterator<Item = &'a str>) -> Option<&Value> {
+        path.into_iter().try_fold(self, |value, key| match value {
+            Value::Object(map) => map.get(key),
+            Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+            _ => None,
+        })
+    }
+
+    fn get_path_mut<'a>(&mut self, path: impl IntoIterator<Item = &'a str>) -> Option<&mut Value> {
+        path.into_iter().try_fold(self, |value, key| match value {
+            Value::Object(map) => map.get_mut(key),
+            Value::Array(items) => items.get_mut(key.parse::<usize>().ok()?),
+            _ => None,
+        })
+    }
+
+    fn merge(&mut self, other: Value) {
+        match (self, other) {
+            (Value::Object(map), Value::Object(other)) => {
+                for (key, value) in other {
+                    match map.get_mut(&key) {
+                        Some(existing) => existing.merge(value),
+                        None => {
+                            map.insert(key, value);
+                        }
+                    }
+                }
+            }
+            (this, other) => *this = other,
+        }
+    }
+
+    fn as_table_string(&self) -> String {
+        let (rows, numbered): (Vec<_>, _) = match self {
+            Value::Object(map) if !map.is_empty() => {
+                let rows = map
+                    .iter()
+                    .map(|(k, v)| (k.escape_debug().to_string(), table_cell(v)));
+                (rows.collect(), false)
+            }
+            Value::Array(items) if !items.is_empty() => {
+                let rows = items
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| (i.to_string(), table_cell(v)));
+                (rows.collect(), true)
+            }
+            other => return other.to_string(),
+        };
+        let width = |cell: fn(&(String, String)) -> &String| {
+            rows.iter().map(|r| cell(r).chars().count()).max().unwrap()
+        };
+        let (w1, w2) = (width(|r| &r.0), width(|r| &r.1));
+        let border = |left, mid, right| {
+            format!(
+                "{}{}{}{}{}\n",
+                left,
+                "─".repeat(w1 + 2),
+                mid,
+                "─".repeat(w2 + 2),
+                right
+            )
+        };
+
+        let mut table = border('┌', '┬', '┐');
+        for (key, value) in &rows {
+            if numbered {
+                table += &format!("│ {:>w1$} │ {:<w2$} │\n", key, value, w1 = w1, w2 = w2);
+            } else {
+                table += &format!("│ {:<w1$} │ {:<w2$} │\n", key, value, w1 = w1, w2 = w2);
+            }
+        }
+        table += &border('└', '┴', '┘');
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn output(options: &OutputOptions) -> String {
+        let value = json!({ "b": [1, "é😀"], "a": { "d": null, "c": true } });
+        let mut buf = Vec::new();
+        options.serialize_to(&mut buf, &value).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn stream() {
+        let input = r#"{"a": 1} 2 {"a": 3}
+        {"a": 4}"#;
+        let mut output = Vec::new();
+        let mut run = StreamFn(|v: Value| match v.get("a") {
+            Some(a) if a == 3 => bail!("three"),
+            Some(_) => Ok(Some(v)),
+            None => Ok(None),
+        });
+        let options = OutputOptions::default();
+        let throttle = &mut Throttle::new(None);
+        let err = run_json_stream_impl(input.as_bytes(), &mut output, &mut run, &options, throttle)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "failed to process record 2");
+        assert_eq!(output, b"{\"a\":1}\n");
+
+        output.clear();
+        let input = r#"{"a": 1} 2 {"a": 4}"#;
+        let count =
+            run_json_stream_impl(input.as_bytes(), &mut output, &mut run, &options, throttle)
+                .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(output, b"{\"a\":1}\n{\"a\":4}\n");
+    }
+
+    #[test]
+    fn rate_limit() -> Result<()> {
+        assert_eq!("0.5".parse::<RateLimit>()?, RateLimit(0.5));
+        for bad in ["0", "-1", "inf", "NaN", "x"] {
+            assert!(bad.parse::<RateLimit>().is_err(), "{}", bad);
+        }
+
+        let input = "1 2 3 4 5";
+        let mut output = Vec::new();
+        let mut run = StreamFn(|v| Ok(Some(v)));
+        let mut throttle = Throttle::new(Some(RateLimit(100.0)));
+        let start = Instant::now();
+        let options = OutputOptions::default();
+        run_json_stream_impl(
+            input.as_bytes(),
+            &mut output,
+            &mut run,
+            &options,
+            &mut throttle,
+        )?;
+        // The first record is written straight away, then one every 10ms
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert_eq!(output, b"1\n2\n3\n4\n5\n");
+
+        // Without a limit nothing waits
+        let mut throttle = Throttle::new(None);
+        let start = Instant::now();
+        run_json_stream_impl(
+            input.as_bytes(),
+            &mut output,
+            &mut run,
+            &options,
+            &mut throttle,
+        )?;
+        assert!(start.elapsed() < Duration::from_millis(40));
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_input() -> Result<()> {
+        let dir = std::env::temp_dir();
+        for (name, gzip_in, zstd_in, codec) in [
+            ("json-tools-test.json.gz", false, false, Codec::Gzip),
+            ("json-tools-test.json.zst", false, false, Codec::Zstd),
+            ("json-tools-test", true, false, Codec::Gzip),
+            ("json-tools-test", false, true, Codec::Zstd),
+            ("json-tools-test.zst", true, false, Codec::Gzip),
+        ] {
+            let path = dir.join(name);
+            let mut encoder = codec.writer(File::create(&path)?)?;
+            encoder.write_all(br#"{"a": 1} [2]"#)?;
+            encoder.finish()?;
+
+            let options = InputOptions {
+                path: Some(path.clone()),
+                gzip_in,
+                zstd_in,
+                ..InputOptions::default()
+            };
+            let mut output = Vec::new();
+            let mut run = StreamFn(|v| Ok(Some(v)));
+            let input = match options.open()? {
+                Input::File(f) => f,
+                Input::Stdin(_) => unreachable!(),
+            };
+            run_json_stream_impl(
+                BufReader::new(input),
+                &mut output,
+                &mut run,
+                &OutputOptions::default(),
+                &mut Throttle::new(None),
+            )?;
+            std::fs::remove_file(&path)?;
+            assert_eq!(output, b"{\"a\":1}\n[2]\n", "{}", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn input_dir() -> Result<()> {
+        let dir = std::env::temp_dir().join("json-tools-test-input-dir");
+        std::fs::create_dir_all(dir.join("sub.json"))?;
+        std::fs::write(dir.join("b.json"), "[2]")?;
+        std::fs::write(dir.join("a.json"), r#"{"a": 1}"#)?;
+        std::fs::write(dir.join("c.txt"), "{")?;
+        let mut encoder = Codec::Gzip.writer(File::create(dir.join("c.json.gz"))?)?;
+        encoder.write_all(b"3")?;
+        encoder.finish()?;
+
+        let run = |glob: Option<&str>| -> Result<Vec<u8>> {
+            let options = InputOptions {
+                input_dir: Some(dir.clone()),
+                glob: glob.map(String::from),
+                ..InputOptions::default()
+            };
+            let mut output = Vec::new();
+            let mut run = StreamFn(|v| Ok(Some(v)));
+            let (format, throttle) = (OutputOptions::default(), &mut Throttle::new(None));
+            options.for_each(|input| {
+                run_json_stream_input(input, &mut output, &mut run, &format, throttle)?;
+                Ok(())
+            })?;
+            Ok(output)
+        };
+        let matched = run(Some("*.json*"));
+        let err = run(None).unwrap_err();
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(matched?, b"{\"a\":1}\n[2]\n3\n");
+        let message = format!("{:#}", err);
+        assert!(message.contains("c.txt"), "{}", message);
+        Ok(())
+    }
+
+    #[test]
+    fn expect_errors() {
+        let err = json!([1]).expect_object().unwrap_err();
+        assert_eq!(
+            err,
+            JsonToolsError::TypeMismatch {
+                expected: "object",
+                found: "array"
+            }
+        );
+        assert_eq!(err.to_string(), "expected JSON object, not array");
+        assert_eq!(
+            json!("1").expect_int().unwrap_err().to_string(),
+            "expected JSON number, not string"
+        );
+        assert_eq!(
+            json!(-1).expect_uint().unwrap_err().to_string(),
+            "cannot convert to unsigned integer: -1"
+        );
+        assert!(matches!(
+            json!(1.5).expect_int(),
+            Err(JsonToolsError::NumberConversion {
+                target: "integer",
+                ..
+            })
+        ));
+        assert_eq!(json!(2).expect_uint(), Ok(2));
+
+        let value = json!({ "a": ["x"] });
+        let items = value.expect_obj().unwrap()["a"].expect_arr().unwrap();
+        assert_eq!(items[0].expect_str(), Ok("x"));
+        assert_eq!(
+            items[0].expect_obj().unwrap_err().to_string(),
+            "expected JSON object, not string"
+        );
+        assert_eq!(
+            value.expect_str().unwrap_err(),
+            JsonToolsError::TypeMismatch {
+                expected: "string",
+                found: "object"
+            }
+        );
+        assert_eq!(
+            value.expect_arr().unwrap_err().to_string(),
+            "expected JSON array, not object"
+        );
+        assert_eq!(json!(2).expect_f64(), Ok(2.0));
+        assert_eq!(json!(-0.5).expect_f64(), Ok(-0.5));
+        assert_eq!(
+            json!(null).expect_f64().unwrap_err().to_string(),
+            "expected JSON number, not null"
+        );
+    }
+
+    #[test]
+    fn merge() {
+        let mut value = json!({ "a": { "b": 1, "c": [1, 2] }, "d": "x", "e": { "f": 1 }, "g": 1 });
+        value.merge(
+            json!({ "a": { "c": [3], "h": { "i": 2 } }, "e": 2, "d": { "j": 3 }, "g": null }),
+        );
+        assert_eq!(
+            serde_json::to_string(&value).unwrap(),
+            r#"{"a":{"b":1,"c":[3],"h":{"i":2}},"d":{"j":3},"e":2,"g":null}"#
+        );
+
+        let mut value = json!(null);
+        value.merge(json!({ "a": 1 }));
+        assert_eq!(value, json!({ "a": 1 }));
+        value.merge(json!([1]));
+        assert_eq!(value, json!([1]));
+        value.merge(json!([2, 3]));
+        assert_eq!(value, json!([2, 3]));
+    }
+
+    #[test]
+    fn table_string() {
+        let table = json!({ "a": 1, "long key": "two\nlines", "c": { "d": [null] } });
+        assert_eq!(
+            table.as_table_string(),
+            "\
+┌──────────┬──────────────┐
+│ a        │ 1            │
+│ long key │ two\\nlines   │
+│ c        │ {\"d\":[null]} │
+└──────────┴──────────────┘
+"
+        );
+
+        let list: Value = (0..11).map(|i| json!(i * 10)).collect();
+        let lines: Vec<_> = list.as_table_string().lines().map(String::from).collect();
+        assert_eq!(lines[0], "┌────┬─────┐");
+        assert_eq!(lines[1], "│  0 │ 0   │");
+        assert_eq!(lines[11], "│ 10 │ 100 │");
+        assert_eq!(lines[12], "└────┴─────┘");
+
+        assert_eq!(json!({}).as_table_string(), "{}");
+        assert_eq!(json!("a").as_table_string(), r#""a""#);
+    }
+
+    #[test]
+    fn key_case() {
+        let cases = [
+            (
+                "userId",
+                ["user_id", "userId", "user-id", "userid", "USERID"],
+            ),
+            (
+                "user_id",
+                ["user_id", "userId", "user-id", "user_id", "USER_ID"],
+            ),
+            (
+                "User-ID",
+                ["user_id", "userId", "user-id", "user-id", "USER-ID"],
+            ),
+            (
+                "HTTPServer2Port",
+                [
+                    "http_server2_port",
+                    "httpServer2Port",
+                    "http-server2-port",
+                    "httpserver2port",
+                    "HTTPSERVER2PORT",
+                ],
+            ),
+            ("__id__", ["__id__", "__id__", "__id__", "__id__", "__ID__"]),
+            ("a  b", ["a_b", "aB", "a-b", "a  b", "A  B"]),
+            ("_", ["_", "_", "_", "_", "_"]),
+            ("", ["", "", "", "", ""]),
+        ];
+        let styles = ["snake", "camel", "kebab", "lower", "upper"];
+        for (key, expected) in cases {
+            for (style, expected) in styles.into_iter().zip(expected) {
+                let case: KeyCase = style.parse().unwrap();
+                assert_eq!(case.apply(key), expected, "{} as {}", key, style);
+            }
+        }
+        assert!("title".parse::<KeyCase>().is_err());
+    }
+
+    #[test]
+    fn canonical() {
+        let mut a = String::new();
+        write_canonical(
+            &mut a,
+            &json!({ "b": [1, { "d": null, "c": "\"" }], "a": 1.5, "e": 2.0, "f": -0.0 }),
+        );
+        assert_eq!(a, r#"{"a":1.5,"b":[1,{"c":"\"","d":null}],"e":2,"f":0}"#);
+    }
+
+    #[test]
+    fn natural_order() {
+        let mut keys = vec![
+            "a.10", "a.2", "a.b", "a.02", "a", "b.1.10", "b.1.9", "a.1x", "10", "9",
+        ];
+        keys.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            keys,
+            ["9", "10", "a", "a.1x", "a.2", "a.02", "a.10", "a.b", "b.1.9", "b.1.10"]
+        );
+
+        let options = OutputOptions {
+            sort_keys: Some("natural".parse().unwrap()),
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        let value = json!({ "x10": { "b": 1, "a": 2 }, "x9": [{ "y2": 0, "y1": 0 }] });
+        options.serialize_to(&mut buf, &value).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            r#"{"x9":[{"y1":0,"y2":0}],"x10":{"a":2,"b":1}}"#
+        );
+    }
+
+    #[test]
+    fn output_file() -> Result<()> {
+        let path = std::env::temp_dir().join("json-tools-test-output.json");
+        for (gzip_out, expected) in [(false, &b"[1]\n"[..]), (true, &b"\x1f\x8b"[..])] {
+            let options = OutputOptions {
+                output_file: Some(path.clone()),
+                gzip_out,
+                ..Default::default()
+            };
+            options.with_output(|w| {
+                options.serialize_to(&mut *w, &json!([1]))?;
+                writeln!(w)?;
+                Ok(())
+            })?;
+            let written = std::fs::read(&path)?;
+            assert!(written.starts_with(expected), "{:?}", written);
+        }
+        std::fs::remove_file(&path)?;
+
+        let options = OutputOptions {
+            output_file: Some(std::env::temp_dir().join("missing-dir").join("out.json")),
+            ..Default::default()
+        };
+        let err = options.with_output(|_| Ok(())).unwrap_err();
+        assert!(err.to_string().starts_with("failed to create"));
+        Ok(())
+    }
+
+    #[test]
+    fn in_place() -> Result<()> {
+        let dir = std::env::temp_dir().join("json-tools-test-in-place");
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("doc.json");
+        fs::write(&path, "[1]\n")?;
+        let options = OutputOptions {
+            in_place: Some(path.clone()),
+            ..Default::default()
+        };
+
+        let err = options
+            .with_output(|w| -> Result<()> {
+                w.write_all(b"partial")?;
+                bail!("failed")
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "failed");
+        assert_eq!(fs::read_to_string(&path)?, "[1]\n");
+        assert_eq!(fs::read_dir(&dir)?.count(), 1);
+
+        options.with_output(|w| options.serialize_to(w, &json!([2])))?;
+        assert_eq!(fs::read_to_string(&path)?, "[2]");
+        assert_eq!(fs::read_dir(&dir)?.count(), 1);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn parse_indent() {
+        assert_eq!("tab".parse::<Indent>().unwrap(), Indent::Tab);
+        assert_eq!("0".parse::<Indent>().unwrap(), Indent::Spaces(0));
+        assert_eq!("8".parse::<Indent>().unwrap(), Indent::Spaces(8));
+        assert!("-1".parse::<Indent>().is_err());
+        assert!("tabs".parse::<Indent>().is_err());
+    }
+
+    #[test]
+    fn output_options() {
+        let compact = r#"{"b":[1,"é😀"],"a":{"d":null,"c":true}}"#;
+        let compact_sorted = r#"{"a":{"c":true,"d":null},"b":[1,"é😀"]}"#;
+        let pretty = r#"{
+  "b": [
+    1,
+    "é😀"
+  ],
+  "a": {
+    "d": null,
+    "c": true
+  }
+}"#;
+        let pretty_sorted = r#"{
+  "a": {
+    "c": true,
+    "d": null
+  },
+  "b": [
+    1,
+    "é😀"
+  ]
+}"#;
+
+        for layout in ["compact", "pretty", "indent", "tab"] {
+            for sort_keys in [false, true] {
+                for ascii in [false, true] {
+                    let options = OutputOptions {
+                        pretty: layout == "pretty",
+                        indent: match layout {
+                            "indent" => Some(Indent::Spaces(4)),
+                            "tab" => Some(Indent::Tab),
+                            _ => None,
+                        },
+                        sort_keys: sort_keys.then_some(SortKeys::Lexical),
+                        ascii,
+                        ..Default::default()
+                    };
+                    let mut expected = match (layout, sort_keys) {
+                        ("compact", false) => compact.to_string(),
+                        ("compact", true) => compact_sorted.to_string(),
+                        (_, false) => pretty.to_string(),
+                        (_, true) => pretty_sorted.to_string(),
+                    };
+                    match layout {
+                        "indent" => expected = expected.replace("  ", "    "),
+                        "tab" => expected = expected.replace("  ", "\t"),
+                        _ => {}
+                    }
+                    if ascii {
+                        expected = expected.replace("é😀", r"\u00e9\ud83d\ude00");
+                    }
+                    assert_eq!(output(&options), expected, "{:?}", options);
+                }
+            }
+        }
     }
 }